@@ -0,0 +1,152 @@
+//! Compares `/isBlockedBVS`-style lookups through the old single shared
+//! connection against the WAL reader pool, while the spider keeps writing.
+//!
+//! Run with `cargo bench --bench db_pool`.
+
+use fuckbilibili_lib::db;
+use fuckbilibili_lib::pool::DbPool;
+use rusqlite::Connection;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+const USERS: i64 = 10_000;
+const CACHED_BVS: i64 = 50_000;
+const TABS: usize = 32;
+const REQUESTS_PER_TAB: usize = 200;
+const BVS_PER_REQUEST: i64 = 30;
+
+fn seed(path: &Path) {
+    let mut conn = db::init_db(path).unwrap();
+    let tx = conn.transaction().unwrap();
+    for mid in 0..USERS {
+        db::add_user(&tx, mid * 7, None).unwrap();
+    }
+    for i in 0..CACHED_BVS {
+        db::cache_bv_mid(&tx, &format!("BV{}", i), i % (USERS * 3)).unwrap();
+    }
+    tx.commit().unwrap();
+}
+
+/// Same queries the handler runs for one request
+fn lookup(conn: &Connection, tab: usize, req: usize) -> usize {
+    let mut blocked = 0;
+    for j in 0..BVS_PER_REQUEST {
+        let bv = format!("BV{}", (tab as i64 * 7919 + req as i64 * 31 + j) % CACHED_BVS);
+        if let Ok(Some(mid)) = db::get_mid_by_bv(conn, &bv) {
+            if db::is_user_exist(conn, mid).unwrap_or(false) {
+                blocked += 1;
+            }
+        }
+    }
+    blocked
+}
+
+trait Backend: Send + Sync + 'static {
+    fn read(&self, tab: usize, req: usize) -> impl std::future::Future<Output = usize> + Send;
+    fn write(&self, i: i64) -> impl std::future::Future<Output = ()> + Send;
+}
+
+struct SingleConnection(Mutex<Connection>);
+
+impl Backend for SingleConnection {
+    async fn read(&self, tab: usize, req: usize) -> usize {
+        let conn = self.0.lock().await;
+        lookup(&conn, tab, req)
+    }
+
+    async fn write(&self, i: i64) {
+        let conn = self.0.lock().await;
+        db::cache_bv_mid(&conn, &format!("NEW{}", i), i).unwrap();
+    }
+}
+
+impl Backend for DbPool {
+    async fn read(&self, tab: usize, req: usize) -> usize {
+        let conn = DbPool::read(self).await;
+        lookup(&conn, tab, req)
+    }
+
+    async fn write(&self, i: i64) {
+        let conn = DbPool::write(self).await;
+        db::cache_bv_mid(&conn, &format!("NEW{}", i), i).unwrap();
+    }
+}
+
+async fn run<B: Backend>(name: &str, backend: Arc<B>) {
+    let stop = Arc::new(AtomicBool::new(false));
+    let writes = Arc::new(AtomicUsize::new(0));
+
+    // Spider caching results in the background
+    let writer = {
+        let (backend, stop, writes) = (backend.clone(), stop.clone(), writes.clone());
+        tokio::spawn(async move {
+            let mut i = 0;
+            while !stop.load(Ordering::Relaxed) {
+                backend.write(i).await;
+                writes.fetch_add(1, Ordering::Relaxed);
+                i += 1;
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+    };
+
+    let start = Instant::now();
+    let tabs: Vec<_> = (0..TABS)
+        .map(|tab| {
+            let backend = backend.clone();
+            tokio::spawn(async move {
+                for req in 0..REQUESTS_PER_TAB {
+                    backend.read(tab, req).await;
+                    tokio::task::yield_now().await;
+                }
+            })
+        })
+        .collect();
+    for tab in tabs {
+        tab.await.unwrap();
+    }
+    let elapsed = start.elapsed();
+
+    stop.store(true, Ordering::Relaxed);
+    writer.await.unwrap();
+
+    let requests = TABS * REQUESTS_PER_TAB;
+    println!(
+        "{:<18} {:>8.1} ms  {:>9.0} req/s  ({} writes interleaved)",
+        name,
+        elapsed.as_secs_f64() * 1000.0,
+        requests as f64 / elapsed.as_secs_f64(),
+        writes.load(Ordering::Relaxed)
+    );
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(8)
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let dir = tempfile::tempdir().unwrap();
+    println!(
+        "{} tabs x {} requests x {} BVs, 8 worker threads",
+        TABS, REQUESTS_PER_TAB, BVS_PER_REQUEST
+    );
+
+    runtime.block_on(async {
+        let single_path = dir.path().join("single.db");
+        seed(&single_path);
+        let single = SingleConnection(Mutex::new(db::init_db(&single_path).unwrap()));
+        run("single connection", Arc::new(single)).await;
+
+        for readers in [2, 4, 8] {
+            let pool_path = dir.path().join(format!("pool{}.db", readers));
+            seed(&pool_path);
+            let pool = DbPool::open(&pool_path, readers).unwrap();
+            run(&format!("pool ({} readers)", readers), Arc::new(pool)).await;
+        }
+    });
+}
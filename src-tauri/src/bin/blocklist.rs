@@ -0,0 +1,189 @@
+//! Command-line maintenance tool for the blocklist database.
+//!
+//! Every command prints JSON to stdout so the output can be piped into other tools.
+
+use fuckbilibili_lib::db::{self, BlockedUser};
+use fuckbilibili_lib::paths::AppPaths;
+use fuckbilibili_lib::server;
+use rusqlite::Connection;
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::process::ExitCode;
+use std::time::Duration;
+
+const USAGE: &str = "Usage: blocklist [--db <path>] <command> [args]
+
+The database defaults to blocked_users.db in the app data directory
+(override with FUCKBILIBILI_DATA_DIR).
+
+Commands:
+  add <mid> [username]   Block a user
+  remove <mid>           Unblock a user
+  list                   List all blocked users
+  search <keyword>       Search blocked users by mid or username
+  import <file|->        Import users from a JSON array of {mid, username}
+  export [file|-]        Export all users as a JSON array
+  vacuum                 Compact the database file
+  stats                  Show database statistics
+
+add, remove and import ask a running app to reload the blocklist; if none
+answers, a running app only sees the change after a restart.";
+
+/// Commands that change the blocklist
+const WRITE_COMMANDS: [&str; 3] = ["add", "remove", "import"];
+
+fn main() -> ExitCode {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+
+    let db_path = match args.iter().position(|a| a == "--db") {
+        Some(pos) => {
+            if pos + 1 >= args.len() {
+                eprintln!("{}", USAGE);
+                return ExitCode::from(2);
+            }
+            let path = args.remove(pos + 1);
+            args.remove(pos);
+            path
+        }
+        None => {
+            let paths = AppPaths::standard();
+            let _ = paths.create_dirs();
+            // Before init_db creates an empty database where the old one goes
+            paths.migrate_legacy();
+            paths.db_file().to_string_lossy().into_owned()
+        }
+    };
+
+    if args.is_empty() || args[0] == "-h" || args[0] == "--help" {
+        eprintln!("{}", USAGE);
+        return ExitCode::from(2);
+    }
+
+    let mut conn = match db::init_db(&db_path) {
+        Ok(c) => c,
+        Err(e) => return fail(&format!("Failed to open {}: {}", db_path, e)),
+    };
+
+    match run_command(&mut conn, &db_path, &args[0], &args[1..]) {
+        Ok(mut output) => {
+            if WRITE_COMMANDS.contains(&args[0].as_str()) {
+                let reloaded = reload_server();
+                if !reloaded {
+                    eprintln!("No running app answered; restart it if it is running to apply the change");
+                }
+                if let Value::Object(fields) = &mut output {
+                    fields.insert("server_reloaded".to_string(), json!(reloaded));
+                }
+            }
+            println!("{}", serde_json::to_string_pretty(&output).unwrap());
+            ExitCode::SUCCESS
+        }
+        Err(e) => fail(&e),
+    }
+}
+
+fn run_command(
+    conn: &mut Connection,
+    db_path: &str,
+    command: &str,
+    args: &[String],
+) -> Result<Value, String> {
+    match command {
+        "add" => {
+            let mid = parse_mid(args.first())?;
+            let added = db::add_user(conn, mid, args.get(1).map(String::as_str)).map_err(|e| e.to_string())?;
+            Ok(json!({ "mid": mid, "added": added }))
+        }
+        "remove" => {
+            let mid = parse_mid(args.first())?;
+            let removed = db::remove_user(conn, mid).map_err(|e| e.to_string())?;
+            Ok(json!({ "mid": mid, "removed": removed }))
+        }
+        "list" => {
+            let users = db::list_users(conn).map_err(|e| e.to_string())?;
+            Ok(json!(users))
+        }
+        "search" => {
+            let keyword = args.first().ok_or("search requires a keyword")?;
+            let users = db::search_users(conn, keyword).map_err(|e| e.to_string())?;
+            Ok(json!(users))
+        }
+        "import" => {
+            let source = args.first().ok_or("import requires a file path or '-'")?;
+            let content = if source == "-" {
+                let mut buf = String::new();
+                io::stdin().read_to_string(&mut buf).map_err(|e| e.to_string())?;
+                buf
+            } else {
+                fs::read_to_string(source).map_err(|e| format!("Failed to read {}: {}", source, e))?
+            };
+            let users: Vec<BlockedUser> = serde_json::from_str(&content).map_err(|e| format!("Invalid import data: {}", e))?;
+            let added = db::add_users(conn, &users).map_err(|e| e.to_string())?;
+            Ok(json!({ "total": users.len(), "added": added }))
+        }
+        "export" => {
+            let users = db::list_users(conn).map_err(|e| e.to_string())?;
+            match args.first().map(String::as_str) {
+                None | Some("-") => Ok(json!(users)),
+                Some(path) => {
+                    let content = serde_json::to_string_pretty(&users).map_err(|e| e.to_string())?;
+                    fs::write(path, content).map_err(|e| format!("Failed to write {}: {}", path, e))?;
+                    Ok(json!({ "exported": users.len(), "path": path }))
+                }
+            }
+        }
+        "vacuum" => {
+            let before = file_size(db_path);
+            db::vacuum(conn).map_err(|e| e.to_string())?;
+            Ok(json!({ "size_before": before, "size_after": file_size(db_path) }))
+        }
+        "stats" => {
+            let blocked = db::get_blocked_count(conn).map_err(|e| e.to_string())?;
+            let cached = db::get_bv_cache_count(conn).map_err(|e| e.to_string())?;
+            Ok(json!({
+                "db_path": db_path,
+                "db_size": file_size(db_path),
+                "blocked_count": blocked,
+                "bv_cache_count": cached,
+            }))
+        }
+        _ => Err(format!("Unknown command '{}'\n\n{}", command, USAGE)),
+    }
+}
+
+/// Positive and digits only, like the server's `/block`
+fn parse_mid(arg: Option<&String>) -> Result<i64, String> {
+    let arg = arg.ok_or("Missing mid")?;
+    match arg.parse::<i64>() {
+        Ok(mid) if mid > 0 && arg.chars().all(|c| c.is_ascii_digit()) => Ok(mid),
+        _ => Err(format!("Invalid mid '{}'", arg)),
+    }
+}
+
+/// Asks the app's HTTP server, if one is running, to reload the blocklist.
+/// False if none answered.
+fn reload_server() -> bool {
+    let addr = SocketAddr::from(([127, 0, 0, 1], server::DEFAULT_ADDR.1));
+    let Ok(mut stream) = TcpStream::connect_timeout(&addr, Duration::from_millis(500)) else {
+        return false;
+    };
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(2)));
+    let request = "POST /reload HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+    if stream.write_all(request.as_bytes()).is_err() {
+        return false;
+    }
+    let mut response = String::new();
+    let _ = stream.read_to_string(&mut response);
+    response.starts_with("HTTP/1.1 200") && response.ends_with("OK")
+}
+
+fn file_size(path: &str) -> u64 {
+    fs::metadata(path).map(|m| m.len()).unwrap_or(0)
+}
+
+fn fail(message: &str) -> ExitCode {
+    eprintln!("{}", json!({ "error": message }));
+    ExitCode::FAILURE
+}
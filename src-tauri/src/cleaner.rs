@@ -0,0 +1,65 @@
+use crate::config::ConfigManager;
+use crate::db;
+use crate::state::AppState;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+pub async fn start_cleaner(state: Arc<AppState>, config: Arc<ConfigManager>) {
+    // Initial sleep to let app startup
+    if !sleep_or_shutdown(&state, 10).await {
+        return;
+    }
+
+    loop {
+        purge_expired_blocks(&state).await;
+
+        let days = config.get_config().cache_expiration_days;
+        if days > 0 {
+            let secs = (days * 24 * 3600) as i64;
+            // Use a block to drop the lock after operation
+            {
+                let conn = state.db.write().await;
+                if let Ok(deleted) = db::clean_expired_cache(&conn, secs) {
+                    state.spider_stats.session_cleaned_count.fetch_add(deleted, Ordering::Relaxed);
+                    if deleted > 0 {
+                        state.index.clear_bvs();
+                    }
+                    // Update stats
+                    if let Ok(count) = db::get_bv_cache_count(&conn) {
+                        state.spider_stats.bv_cache_count.store(count, Ordering::Relaxed);
+                    }
+                } else {
+                    eprintln!("Failed to clean cache");
+                }
+            }
+        }
+        // Check every hour
+        if !sleep_or_shutdown(&state, 3600).await {
+            return;
+        }
+    }
+}
+
+/// Moves temporary blocks that ran out to the block history. They already
+/// stopped counting when they expired, this just tidies up.
+async fn purge_expired_blocks(state: &AppState) {
+    let conn = state.db.write().await;
+    match db::purge_expired_blocks(&conn, chrono::Utc::now().timestamp()) {
+        Ok(mids) if !mids.is_empty() => {
+            for mid in mids {
+                state.index.remove_blocked(mid);
+            }
+            state.recount_blocked_users(&conn);
+        }
+        Ok(_) => {}
+        Err(_) => eprintln!("Failed to purge expired blocks"),
+    }
+}
+
+/// Returns false if the app started shutting down while sleeping.
+async fn sleep_or_shutdown(state: &AppState, secs: u64) -> bool {
+    tokio::select! {
+        _ = tokio::time::sleep(tokio::time::Duration::from_secs(secs)) => true,
+        _ = state.shutdown_token.cancelled() => false,
+    }
+}
@@ -1,49 +1,158 @@
 use serde::{Deserialize, Serialize};
-use std::fs;
-use std::path::Path;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
 
+/// Kept apart from config.json so the session never ends up in a shared config
+const COOKIES_FILE: &str = "cookies.json";
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct AppConfig {
     pub cache_expiration_days: u64,
     pub proxy_url: Option<String>,
+    /// Proxies the spider rotates among, in addition to `proxy_url`
+    #[serde(default)]
+    pub proxy_urls: Vec<String>,
     #[serde(default)]
     pub proxy_enabled: bool,
     #[serde(default = "default_theme")]
     pub theme: String,
+    #[serde(default)]
+    pub fetch_strategy: FetchStrategy,
+    #[serde(default)]
+    pub queue_overflow: OverflowPolicy,
+    /// Queued BVs older than this are skipped instead of fetched, 0 keeps them
+    #[serde(default = "default_queue_ttl_secs")]
+    pub queue_ttl_secs: u64,
+    /// Saved to cookies.json, never to config.json
+    #[serde(default)]
+    pub cookies: BilibiliCookies,
+}
+
+/// Login cookies the spider sends so it is not rate limited as an anonymous client
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct BilibiliCookies {
+    #[serde(default)]
+    pub sessdata: String,
+    #[serde(default)]
+    pub buvid3: String,
+}
+
+impl BilibiliCookies {
+    pub fn is_empty(&self) -> bool {
+        self.sessdata.trim().is_empty() && self.buvid3.trim().is_empty()
+    }
+
+    pub fn has_session(&self) -> bool {
+        !self.sessdata.trim().is_empty()
+    }
+
+    /// Value for the `Cookie` request header, `None` if nothing is set.
+    pub fn header(&self) -> Option<String> {
+        let pairs: Vec<String> = [("SESSDATA", &self.sessdata), ("buvid3", &self.buvid3)]
+            .into_iter()
+            .filter(|(_, value)| !value.trim().is_empty())
+            .map(|(name, value)| format!("{}={}", name, value.trim()))
+            .collect();
+        if pairs.is_empty() {
+            None
+        } else {
+            Some(pairs.join("; "))
+        }
+    }
+}
+
+/// How the spider asks Bilibili for BV owners
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FetchStrategy {
+    /// One `x/web-interface/view` request per BV
+    #[default]
+    Single,
+    /// Many BVs per `x/article/cards` request, per-BV fallback on error
+    Batch,
+}
+
+/// What happens to a BV that arrives while the spider queue is full
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Evict the BV that has waited longest to make room
+    #[default]
+    DropOldest,
+    /// Do not queue the new BV, it is answered "None" as usual
+    DropNewest,
+    /// Do not queue the new BV and answer "Busy" for it
+    Reject,
 }
 
 fn default_theme() -> String {
     "light".to_string()
 }
 
+fn default_queue_ttl_secs() -> u64 {
+    120
+}
+
 impl Default for AppConfig {
     fn default() -> Self {
         Self {
             cache_expiration_days: 7,
             proxy_url: None,
+            proxy_urls: Vec::new(),
             proxy_enabled: false,
             theme: "light".to_string(),
+            fetch_strategy: FetchStrategy::Single,
+            queue_overflow: OverflowPolicy::DropOldest,
+            queue_ttl_secs: default_queue_ttl_secs(),
+            cookies: BilibiliCookies::default(),
+        }
+    }
+}
+
+impl AppConfig {
+    /// Every configured proxy, without blanks or duplicates. Empty when
+    /// proxies are disabled.
+    pub fn proxies(&self) -> Vec<String> {
+        if !self.proxy_enabled {
+            return Vec::new();
         }
+        let mut proxies: Vec<String> = Vec::new();
+        for url in self.proxy_url.iter().chain(&self.proxy_urls) {
+            let url = url.trim();
+            if !url.is_empty() && !proxies.iter().any(|p| p == url) {
+                proxies.push(url.to_string());
+            }
+        }
+        proxies
     }
 }
 
 pub struct ConfigManager {
-    file_path: String,
+    file_path: PathBuf,
+    cookies_path: PathBuf,
     config: Mutex<AppConfig>,
 }
 
 impl ConfigManager {
-    pub fn new(file_path: &str) -> Self {
-        let config = if Path::new(file_path).exists() {
+    pub fn new<P: AsRef<Path>>(file_path: P) -> Self {
+        let file_path = file_path.as_ref();
+        let mut config: AppConfig = if file_path.exists() {
             let content = fs::read_to_string(file_path).unwrap_or_else(|_| "{}".to_string());
             serde_json::from_str(&content).unwrap_or_default()
         } else {
             AppConfig::default()
         };
 
+        let cookies_path = file_path.with_file_name(COOKIES_FILE);
+        if let Ok(content) = fs::read_to_string(&cookies_path) {
+            config.cookies = serde_json::from_str(&content).unwrap_or_default();
+        }
+
         Self {
-            file_path: file_path.to_string(),
+            file_path: file_path.to_path_buf(),
+            cookies_path,
             config: Mutex::new(config),
         }
     }
@@ -53,9 +162,45 @@ impl ConfigManager {
     }
 
     pub fn set_config(&self, new_config: AppConfig) -> Result<(), String> {
-        let json = serde_json::to_string_pretty(&new_config).map_err(|e| e.to_string())?;
+        let shared = AppConfig {
+            cookies: BilibiliCookies::default(),
+            ..new_config.clone()
+        };
+        let json = serde_json::to_string_pretty(&shared).map_err(|e| e.to_string())?;
         fs::write(&self.file_path, json).map_err(|e| e.to_string())?;
+        self.save_cookies(&new_config.cookies).map_err(|e| e.to_string())?;
         *self.config.lock().unwrap() = new_config;
         Ok(())
     }
+
+    fn save_cookies(&self, cookies: &BilibiliCookies) -> io::Result<()> {
+        if cookies.is_empty() {
+            return match fs::remove_file(&self.cookies_path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+        let json = serde_json::to_string_pretty(cookies)?;
+        write_private(&self.cookies_path, json.as_bytes())
+    }
+}
+
+/// Writes a file only the current user can read (0600 on unix).
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+
+    // The mode above only applies when the file is created
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(contents)
 }
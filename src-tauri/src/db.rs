@@ -1,13 +1,47 @@
-use rusqlite::{params, Connection, Result};
+use rusqlite::{params, params_from_iter, Connection, Error, OptionalExtension, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::Path;
 
+use crate::rules::{self, Rule};
+
 pub fn init_db<P: AsRef<Path>>(path: P) -> Result<Connection> {
     let conn = Connection::open(path)?;
 
     conn.execute(
         "CREATE TABLE IF NOT EXISTS users (
             mid INTEGER PRIMARY KEY,
-            username TEXT
+            username TEXT,
+            expires_at INTEGER,
+            list_id INTEGER NOT NULL DEFAULT 1
+        )",
+        [],
+    )?;
+    add_column(&conn, "users", "expires_at", "INTEGER")?;
+    add_column(&conn, "users", "list_id", "INTEGER NOT NULL DEFAULT 1")?;
+
+    // Named categories of blocks that can be switched off as a whole
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS block_lists (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL UNIQUE,
+            enabled INTEGER NOT NULL DEFAULT 1
+        )",
+        [],
+    )?;
+    conn.execute(
+        "INSERT OR IGNORE INTO block_lists (id, name) VALUES (?, ?)",
+        params![DEFAULT_LIST, DEFAULT_LIST_NAME],
+    )?;
+
+    // Temporary blocks that ran out
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS block_history (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            mid INTEGER NOT NULL,
+            username TEXT,
+            expires_at INTEGER,
+            ended_at INTEGER NOT NULL
         )",
         [],
     )?;
@@ -20,14 +54,138 @@ pub fn init_db<P: AsRef<Path>>(path: P) -> Result<Connection> {
         )",
         [],
     )?;
+    for (column, definition) in VIDEO_COLUMNS {
+        add_column(&conn, "bv_cache", column, definition)?;
+    }
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS spider_queue (
+            bvid TEXT PRIMARY KEY,
+            queued_at INTEGER
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS user_profiles (
+            mid INTEGER PRIMARY KEY,
+            name TEXT,
+            face TEXT,
+            fans INTEGER,
+            level INTEGER,
+            join_time INTEGER,
+            updated_at INTEGER
+        )",
+        [],
+    )?;
+    add_column(&conn, "user_profiles", "join_time", "INTEGER")?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS rules (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL,
+            definition TEXT NOT NULL,
+            enabled INTEGER NOT NULL DEFAULT 1
+        )",
+        [],
+    )?;
+    migrate_json_rules(&conn)?;
 
     Ok(conn)
 }
 
+/// Rewrites rules stored as JSON conditions into expressions. Ones that do
+/// not convert are left as they are, and listed but not applied.
+fn migrate_json_rules(conn: &Connection) -> Result<()> {
+    let mut stmt = conn.prepare("SELECT id, definition FROM rules WHERE definition LIKE '{%'")?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))?;
+    for row in rows {
+        let (id, json) = row?;
+        if let Some(expression) = rules::expression_from_json(&json) {
+            conn.execute("UPDATE rules SET definition = ? WHERE id = ?", params![expression, id])?;
+        }
+    }
+    Ok(())
+}
+
+/// Adds `column` to a table created before the column existed.
+fn add_column(conn: &Connection, table: &str, column: &str, definition: &str) -> Result<()> {
+    let mut stmt = conn.prepare(&format!("SELECT 1 FROM pragma_table_info('{}') WHERE name = ?", table))?;
+    if !stmt.exists(params![column])? {
+        conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), [])?;
+    }
+    Ok(())
+}
+
+/// Block list that blocks go to unless they name one
+pub const DEFAULT_LIST: i64 = 1;
+pub const DEFAULT_LIST_NAME: &str = "default";
+
+/// SQL condition for rows of users that block right now
+const ACTIVE_BLOCK: &str = "(expires_at IS NULL OR expires_at > ?)
+     AND list_id IN (SELECT id FROM block_lists WHERE enabled)";
+
 pub fn add_user(conn: &Connection, mid: i64, username: Option<&str>) -> Result<bool> {
-    let mut stmt = conn.prepare("INSERT OR IGNORE INTO users (mid, username) VALUES (?, ?)")?;
-    let rows = stmt.execute(params![mid, username])?;
-    Ok(rows > 0)
+    add_user_to(conn, mid, username, DEFAULT_LIST, None)
+}
+
+/// Blocks `mid` in list `list_id` until `expires_at` (Unix seconds), or for
+/// good with `None`. Replaces a temporary block already in place, and moves
+/// a block in another list, which stays permanent if it was; false if `mid`
+/// is blocked for good in `list_id`.
+pub fn add_user_to(
+    conn: &Connection,
+    mid: i64,
+    username: Option<&str>,
+    list_id: i64,
+    expires_at: Option<i64>,
+) -> Result<bool> {
+    Ok(upsert_block(conn, mid, username, list_id, expires_at)?.is_some())
+}
+
+/// `add_user_to`, returning the end of the block as stored: `None` if it was
+/// refused, `Some(None)` for a permanent block.
+fn upsert_block(
+    conn: &Connection,
+    mid: i64,
+    username: Option<&str>,
+    list_id: i64,
+    expires_at: Option<i64>,
+) -> Result<Option<Option<i64>>> {
+    conn.query_row(
+        "INSERT INTO users (mid, username, expires_at, list_id) VALUES (?, ?, ?, ?)
+         ON CONFLICT(mid) DO UPDATE SET
+             username = COALESCE(excluded.username, users.username),
+             expires_at = CASE WHEN users.expires_at IS NULL THEN NULL ELSE excluded.expires_at END,
+             list_id = excluded.list_id
+         WHERE users.expires_at IS NOT NULL OR users.list_id != excluded.list_id
+         RETURNING expires_at",
+        params![mid, username, expires_at, list_id],
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+/// `add_user_to` the list called `list`, creating it, or the default list
+/// with `None`. Returns the list's id and the end of the block as stored,
+/// `None` if the block was refused; the list is then not created either.
+pub fn block_in_list(
+    conn: &Connection,
+    mid: i64,
+    username: Option<&str>,
+    list: Option<&str>,
+    expires_at: Option<i64>,
+) -> Result<Option<(i64, Option<i64>)>> {
+    let tx = conn.unchecked_transaction()?;
+    let list_id = match list {
+        Some(name) => ensure_block_list(&tx, name)?,
+        None => DEFAULT_LIST,
+    };
+    let Some(expires_at) = upsert_block(&tx, mid, username, list_id, expires_at)? else {
+        return Ok(None);
+    };
+    tx.commit()?;
+    Ok(Some((list_id, expires_at)))
 }
 
 pub fn remove_user(conn: &Connection, mid: i64) -> Result<bool> {
@@ -35,9 +193,11 @@ pub fn remove_user(conn: &Connection, mid: i64) -> Result<bool> {
     Ok(rows > 0)
 }
 
+/// Whether `mid` is blocked in an enabled list; a temporary block that
+/// expired no longer counts.
 pub fn is_user_exist(conn: &Connection, mid: i64) -> Result<bool> {
-    let mut stmt = conn.prepare("SELECT 1 FROM users WHERE mid = ?")?;
-    let exists = stmt.exists(params![mid])?;
+    let mut stmt = conn.prepare(&format!("SELECT 1 FROM users WHERE mid = ? AND {}", ACTIVE_BLOCK))?;
+    let exists = stmt.exists(params![mid, chrono::Utc::now().timestamp()])?;
     Ok(exists)
 }
 
@@ -60,8 +220,109 @@ pub fn cache_bv_mid(conn: &Connection, bvid: &str, mid: i64) -> Result<()> {
     Ok(())
 }
 
+/// Video metadata kept in bv_cache next to the owner, for rules
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct VideoInfo {
+    pub title: Option<String>,
+    /// Partition id and name
+    pub tid: Option<i32>,
+    pub tname: Option<String>,
+    /// Seconds
+    pub duration: Option<i64>,
+    /// Publish time, Unix seconds
+    pub pubdate: Option<i64>,
+    /// None until fetched, which only happens while a rule uses tags
+    pub tags: Option<Vec<String>>,
+}
+
+/// Columns added to bv_cache after its first version
+const VIDEO_COLUMNS: [(&str, &str); 6] = [
+    ("title", "TEXT"),
+    ("tid", "INTEGER"),
+    ("tname", "TEXT"),
+    ("duration", "INTEGER"),
+    ("pubdate", "INTEGER"),
+    // JSON array of tag names, NULL when not fetched
+    ("tags", "TEXT"),
+];
+
+/// Like `cache_bv_mid`, also storing what else is known about the video.
+pub fn cache_video(conn: &Connection, bvid: &str, mid: i64, info: &VideoInfo) -> Result<()> {
+    let tags = tags_json(info.tags.as_deref())?;
+    conn.execute(
+        "INSERT OR REPLACE INTO bv_cache (bvid, mid, updated_at, title, tid, tname, duration, pubdate, tags)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        params![
+            bvid,
+            mid,
+            chrono::Utc::now().timestamp(),
+            info.title,
+            info.tid,
+            info.tname,
+            info.duration,
+            info.pubdate,
+            tags
+        ],
+    )?;
+    Ok(())
+}
+
+fn tags_json(tags: Option<&[String]>) -> Result<Option<String>> {
+    tags.map(serde_json::to_string)
+        .transpose()
+        .map_err(|e| Error::ToSqlConversionFailure(Box::new(e)))
+}
+
+/// Stores the tags of a cached video. Returns false when it is not cached.
+pub fn set_video_tags(conn: &Connection, bvid: &str, tags: &[String]) -> Result<bool> {
+    let rows = conn.execute("UPDATE bv_cache SET tags = ? WHERE bvid = ?", params![tags_json(Some(tags))?, bvid])?;
+    Ok(rows > 0)
+}
+
+fn video_from_row(row: &rusqlite::Row, first: usize) -> Result<VideoInfo> {
+    let tags: Option<String> = row.get(first + 5)?;
+    Ok(VideoInfo {
+        title: row.get(first)?,
+        tid: row.get(first + 1)?,
+        tname: row.get(first + 2)?,
+        duration: row.get(first + 3)?,
+        pubdate: row.get(first + 4)?,
+        tags: tags.and_then(|t| serde_json::from_str(&t).ok()),
+    })
+}
+
+/// Cached metadata of many BVs in one transaction, keyed by BV.
+pub fn get_videos(conn: &Connection, bvids: &[&str]) -> Result<HashMap<String, VideoInfo>> {
+    let tx = conn.unchecked_transaction()?;
+    let mut found = HashMap::new();
+    for chunk in bvids.chunks(BATCH_CHUNK) {
+        let sql = format!(
+            "SELECT bvid, title, tid, tname, duration, pubdate, tags FROM bv_cache WHERE bvid IN ({})",
+            placeholders(chunk.len())
+        );
+        let mut stmt = tx.prepare(&sql)?;
+        let mut rows = stmt.query(params_from_iter(chunk))?;
+        while let Some(row) = rows.next()? {
+            found.insert(row.get::<_, String>(0)?, video_from_row(row, 1)?);
+        }
+    }
+    tx.commit()?;
+    Ok(found)
+}
+
+/// Every cached video with its owner, most recently cached first
+pub fn list_videos(conn: &Connection) -> Result<Vec<(String, i64, VideoInfo)>> {
+    let mut stmt = conn.prepare(
+        "SELECT bvid, mid, title, tid, tname, duration, pubdate, tags FROM bv_cache ORDER BY updated_at DESC",
+    )?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, video_from_row(row, 2)?)))?;
+    rows.collect()
+}
+
+/// Users blocked right now, leaving out expired blocks and disabled lists
 pub fn get_blocked_count(conn: &Connection) -> Result<usize> {
-    let count: usize = conn.query_row("SELECT COUNT(*) FROM users", [], |row| row.get(0))?;
+    let sql = format!("SELECT COUNT(*) FROM users WHERE {}", ACTIVE_BLOCK);
+    let count: usize = conn.query_row(&sql, params![chrono::Utc::now().timestamp()], |row| row.get(0))?;
     Ok(count)
 }
 
@@ -75,3 +336,367 @@ pub fn clean_expired_cache(conn: &Connection, expiration_secs: i64) -> Result<us
     let rows = conn.execute("DELETE FROM bv_cache WHERE updated_at < ?", params![threshold])?;
     Ok(rows)
 }
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BlockedUser {
+    pub mid: i64,
+    pub username: Option<String>,
+    /// End of a temporary block, Unix seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+    /// Name of the block list, the default list when missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub list: Option<String>,
+    #[serde(skip)]
+    pub list_id: i64,
+}
+
+const BLOCKED_USER_COLUMNS: &str = "users.mid, users.username, users.expires_at, block_lists.name, users.list_id
+     FROM users LEFT JOIN block_lists ON block_lists.id = users.list_id";
+
+fn blocked_user_from_row(row: &rusqlite::Row) -> Result<BlockedUser> {
+    let list: Option<String> = row.get(3)?;
+    Ok(BlockedUser {
+        mid: row.get(0)?,
+        username: row.get(1)?,
+        expires_at: row.get(2)?,
+        list: list.filter(|name| name != DEFAULT_LIST_NAME),
+        list_id: row.get(4)?,
+    })
+}
+
+/// All blocked users, in enabled lists or not, including temporary blocks
+/// that expired but were not purged yet.
+pub fn list_users(conn: &Connection) -> Result<Vec<BlockedUser>> {
+    let mut stmt = conn.prepare(&format!("SELECT {} ORDER BY users.mid", BLOCKED_USER_COLUMNS))?;
+    let rows = stmt.query_map([], blocked_user_from_row)?;
+    rows.collect()
+}
+
+pub fn search_users(conn: &Connection, keyword: &str) -> Result<Vec<BlockedUser>> {
+    let pattern = format!("%{}%", keyword);
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {}
+         WHERE CAST(users.mid AS TEXT) LIKE ?1 OR users.username LIKE ?1
+         ORDER BY users.mid",
+        BLOCKED_USER_COLUMNS
+    ))?;
+    let rows = stmt.query_map(params![pattern], blocked_user_from_row)?;
+    rows.collect()
+}
+
+/// Inserts all users in a single transaction, creating the lists they name,
+/// returns how many were new.
+pub fn add_users(conn: &mut Connection, users: &[BlockedUser]) -> Result<usize> {
+    let tx = conn.transaction()?;
+    let mut added = 0;
+    {
+        let mut stmt =
+            tx.prepare("INSERT OR IGNORE INTO users (mid, username, expires_at, list_id) VALUES (?, ?, ?, ?)")?;
+        let mut list_ids = HashMap::new();
+        for user in users {
+            let list_id = match &user.list {
+                Some(name) => match list_ids.get(name) {
+                    Some(id) => *id,
+                    None => {
+                        let id = ensure_block_list(&tx, name)?;
+                        list_ids.insert(name.clone(), id);
+                        id
+                    }
+                },
+                None => DEFAULT_LIST,
+            };
+            added += stmt.execute(params![user.mid, user.username, user.expires_at, list_id])?;
+        }
+    }
+    tx.commit()?;
+    Ok(added)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockList {
+    pub id: i64,
+    pub name: String,
+    pub enabled: bool,
+    /// Blocked users in it
+    pub count: usize,
+}
+
+pub fn list_block_lists(conn: &Connection) -> Result<Vec<BlockList>> {
+    let mut stmt = conn.prepare(
+        "SELECT block_lists.id, block_lists.name, block_lists.enabled, COUNT(users.mid)
+         FROM block_lists LEFT JOIN users ON users.list_id = block_lists.id
+         GROUP BY block_lists.id ORDER BY block_lists.id",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok(BlockList {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            enabled: row.get(2)?,
+            count: row.get(3)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// Id of the list called `name`, created (enabled) if there is none.
+pub fn ensure_block_list(conn: &Connection, name: &str) -> Result<i64> {
+    conn.execute("INSERT OR IGNORE INTO block_lists (name) VALUES (?)", params![name])?;
+    conn.query_row("SELECT id FROM block_lists WHERE name = ?", params![name], |row| row.get(0))
+}
+
+/// Switches the list called `name` on or off, returning its id; `None` if
+/// there is no such list.
+pub fn set_block_list_enabled(conn: &Connection, name: &str, enabled: bool) -> Result<Option<i64>> {
+    let mut stmt = conn.prepare("UPDATE block_lists SET enabled = ? WHERE name = ? RETURNING id")?;
+    let mut rows = stmt.query(params![enabled, name])?;
+    match rows.next()? {
+        Some(row) => Ok(Some(row.get(0)?)),
+        None => Ok(None),
+    }
+}
+
+/// A temporary block that ran out
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BlockHistoryEntry {
+    pub mid: i64,
+    pub username: Option<String>,
+    pub expires_at: Option<i64>,
+    /// When it was purged
+    pub ended_at: i64,
+}
+
+/// Moves temporary blocks that expired by `now` to block_history, returning
+/// their mids.
+pub fn purge_expired_blocks(conn: &Connection, now: i64) -> Result<Vec<i64>> {
+    let tx = conn.unchecked_transaction()?;
+    let mids = {
+        let mut stmt = tx.prepare("SELECT mid FROM users WHERE expires_at <= ?")?;
+        let rows = stmt.query_map(params![now], |row| row.get(0))?;
+        rows.collect::<Result<Vec<i64>>>()?
+    };
+    tx.execute(
+        "INSERT INTO block_history (mid, username, expires_at, ended_at)
+         SELECT mid, username, expires_at, ?1 FROM users WHERE expires_at <= ?1",
+        params![now],
+    )?;
+    tx.execute("DELETE FROM users WHERE expires_at <= ?", params![now])?;
+    tx.commit()?;
+    Ok(mids)
+}
+
+/// Most recently ended first
+pub fn list_block_history(conn: &Connection, limit: usize) -> Result<Vec<BlockHistoryEntry>> {
+    let mut stmt = conn.prepare(
+        "SELECT mid, username, expires_at, ended_at FROM block_history ORDER BY id DESC LIMIT ?",
+    )?;
+    let rows = stmt.query_map(params![limit as i64], |row| {
+        Ok(BlockHistoryEntry {
+            mid: row.get(0)?,
+            username: row.get(1)?,
+            expires_at: row.get(2)?,
+            ended_at: row.get(3)?,
+        })
+    })?;
+    rows.collect()
+}
+
+pub fn vacuum(conn: &Connection) -> Result<()> {
+    conn.execute_batch("VACUUM")
+}
+
+/// Persists BVs the spider did not get to before shutdown.
+pub fn save_spider_queue(conn: &mut Connection, bvids: &[String]) -> Result<()> {
+    let tx = conn.transaction()?;
+    {
+        let now = chrono::Utc::now().timestamp();
+        let mut stmt = tx.prepare("INSERT OR IGNORE INTO spider_queue (bvid, queued_at) VALUES (?, ?)")?;
+        for bvid in bvids {
+            stmt.execute(params![bvid, now])?;
+        }
+    }
+    tx.commit()
+}
+
+/// Removes and returns up to `limit` persisted BVs, oldest first.
+pub fn take_spider_queue(conn: &mut Connection, limit: usize) -> Result<Vec<String>> {
+    let tx = conn.transaction()?;
+    let bvids = {
+        let mut stmt = tx.prepare("SELECT bvid FROM spider_queue ORDER BY queued_at LIMIT ?")?;
+        let rows = stmt.query_map(params![limit as i64], |row| row.get(0))?;
+        rows.collect::<Result<Vec<String>>>()?
+    };
+    {
+        let mut stmt = tx.prepare("DELETE FROM spider_queue WHERE bvid = ?")?;
+        for bvid in &bvids {
+            stmt.execute(params![bvid])?;
+        }
+    }
+    tx.commit()?;
+    Ok(bvids)
+}
+
+/// Keeps each IN (...) list well below SQLite's bound-parameter limit
+const BATCH_CHUNK: usize = 500;
+
+fn placeholders(n: usize) -> String {
+    vec!["?"; n].join(",")
+}
+
+/// Resolves many BVs in one transaction. Results are in input order.
+pub fn get_mids_by_bvs(conn: &Connection, bvids: &[&str]) -> Result<Vec<Option<i64>>> {
+    let tx = conn.unchecked_transaction()?;
+    let mut found = HashMap::new();
+    for chunk in bvids.chunks(BATCH_CHUNK) {
+        let sql = format!(
+            "SELECT bvid, mid FROM bv_cache WHERE bvid IN ({})",
+            placeholders(chunk.len())
+        );
+        let mut stmt = tx.prepare(&sql)?;
+        let mut rows = stmt.query(params_from_iter(chunk))?;
+        while let Some(row) = rows.next()? {
+            found.insert(row.get::<_, String>(0)?, row.get::<_, i64>(1)?);
+        }
+    }
+    tx.commit()?;
+    Ok(bvids.iter().map(|bv| found.get(*bv).copied()).collect())
+}
+
+/// Uploader profile as last fetched. All fields are empty for accounts
+/// Bilibili no longer knows.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserProfile {
+    pub mid: i64,
+    pub name: Option<String>,
+    /// Avatar URL
+    pub face: Option<String>,
+    pub fans: Option<i64>,
+    pub level: Option<i32>,
+    /// Registration time, Unix seconds
+    pub join_time: Option<i64>,
+    pub updated_at: i64,
+}
+
+pub fn cache_user_profile(conn: &Connection, profile: &UserProfile) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO user_profiles (mid, name, face, fans, level, join_time, updated_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?)",
+        params![
+            profile.mid,
+            profile.name,
+            profile.face,
+            profile.fans,
+            profile.level,
+            profile.join_time,
+            profile.updated_at
+        ],
+    )?;
+    Ok(())
+}
+
+const PROFILE_COLUMNS: &str = "mid, name, face, fans, level, join_time, updated_at";
+
+fn profile_from_row(row: &rusqlite::Row) -> Result<UserProfile> {
+    Ok(UserProfile {
+        mid: row.get(0)?,
+        name: row.get(1)?,
+        face: row.get(2)?,
+        fans: row.get(3)?,
+        level: row.get(4)?,
+        join_time: row.get(5)?,
+        updated_at: row.get(6)?,
+    })
+}
+
+pub fn get_user_profile(conn: &Connection, mid: i64) -> Result<Option<UserProfile>> {
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM user_profiles WHERE mid = ?", PROFILE_COLUMNS))?;
+    let mut rows = stmt.query(params![mid])?;
+
+    if let Some(row) = rows.next()? {
+        Ok(Some(profile_from_row(row)?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Cached profiles of many mids in one transaction, keyed by mid.
+pub fn get_user_profiles(conn: &Connection, mids: &[i64]) -> Result<HashMap<i64, UserProfile>> {
+    let tx = conn.unchecked_transaction()?;
+    let mut found = HashMap::new();
+    for chunk in mids.chunks(BATCH_CHUNK) {
+        let sql = format!(
+            "SELECT {} FROM user_profiles WHERE mid IN ({})",
+            PROFILE_COLUMNS,
+            placeholders(chunk.len())
+        );
+        let mut stmt = tx.prepare(&sql)?;
+        let mut rows = stmt.query(params_from_iter(chunk))?;
+        while let Some(row) = rows.next()? {
+            let profile = profile_from_row(row)?;
+            found.insert(profile.mid, profile);
+        }
+    }
+    tx.commit()?;
+    Ok(found)
+}
+
+/// Up to `limit` blocked users without a name whose profile is missing or
+/// older than `max_age_secs`.
+pub fn users_needing_profile(conn: &Connection, max_age_secs: i64, limit: usize) -> Result<Vec<i64>> {
+    let threshold = chrono::Utc::now().timestamp() - max_age_secs;
+    let mut stmt = conn.prepare(
+        "SELECT u.mid FROM users u LEFT JOIN user_profiles p ON p.mid = u.mid
+         WHERE (u.username IS NULL OR u.username = '') AND (p.mid IS NULL OR p.updated_at < ?)
+         ORDER BY u.mid LIMIT ?",
+    )?;
+    let rows = stmt.query_map(params![threshold, limit as i64], |row| row.get(0))?;
+    rows.collect()
+}
+
+/// Sets the name of a blocked user that has none, true if one was set.
+pub fn backfill_username(conn: &Connection, mid: i64, username: &str) -> Result<bool> {
+    let rows = conn.execute(
+        "UPDATE users SET username = ? WHERE mid = ? AND (username IS NULL OR username = '')",
+        params![username, mid],
+    )?;
+    Ok(rows > 0)
+}
+
+pub fn list_rules(conn: &Connection) -> Result<Vec<Rule>> {
+    let mut stmt = conn.prepare("SELECT id, name, definition, enabled FROM rules ORDER BY id")?;
+    let rows = stmt.query_map([], |row| {
+        Ok(Rule {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            enabled: row.get(3)?,
+            expression: row.get(2)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// Inserts a rule with id 0, otherwise updates the rule with its id.
+/// Returns the id.
+pub fn save_rule(conn: &Connection, rule: &Rule) -> Result<i64> {
+    if rule.id == 0 {
+        conn.execute(
+            "INSERT INTO rules (name, definition, enabled) VALUES (?, ?, ?)",
+            params![rule.name, rule.expression, rule.enabled],
+        )?;
+        return Ok(conn.last_insert_rowid());
+    }
+
+    let rows = conn.execute(
+        "UPDATE rules SET name = ?, definition = ?, enabled = ? WHERE id = ?",
+        params![rule.name, rule.expression, rule.enabled, rule.id],
+    )?;
+    if rows == 0 {
+        return Err(Error::QueryReturnedNoRows);
+    }
+    Ok(rule.id)
+}
+
+pub fn delete_rule(conn: &Connection, id: i64) -> Result<bool> {
+    let rows = conn.execute("DELETE FROM rules WHERE id = ?", params![id])?;
+    Ok(rows > 0)
+}
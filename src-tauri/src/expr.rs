@@ -0,0 +1,470 @@
+//! The rule expression language, e.g.
+//! `owner.fans < 500 && (title ~ /抽奖/ || tags == "抽奖")`.
+//!
+//! An expression compares fields of a video and its uploader with literals
+//! and combines the comparisons with `&&`, `||`, `!` and parentheses:
+//!
+//! - numbers: `owner.level`, `owner.fans`, `owner.age_days`, `tid`,
+//!   `duration` (seconds), `age_days`; with `<`, `<=`, `>`, `>=`, `==`, `!=`
+//! - text: `owner.name`, `title`, `tname`; with `==` and `!=` (ignoring
+//!   case), `~` and `!~` against a substring (ignoring case) or a `/regex/`
+//!   (`/regex/i` to ignore case)
+//! - `tags`: like text, true when any tag matches; `!=` and `!~` when none
+//!   does. Tags are only fetched while a rule uses them, so they are unknown
+//!   for videos cached before; those are fetched the next time they are
+//!   checked
+//!
+//! A comparison on data that is not cached is unknown rather than false, and
+//! stays unknown through `!`, so missing data never hides a video.
+
+use regex::{Regex, RegexBuilder};
+
+use crate::rules::Subject;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    OwnerLevel,
+    OwnerFans,
+    /// Days since the account was registered
+    OwnerAgeDays,
+    OwnerName,
+    Title,
+    /// Partition id and name
+    Tid,
+    Tname,
+    Duration,
+    /// Days since the video was published
+    AgeDays,
+    Tags,
+}
+
+const FIELDS: [(&str, Field); 10] = [
+    ("owner.level", Field::OwnerLevel),
+    ("owner.fans", Field::OwnerFans),
+    ("owner.age_days", Field::OwnerAgeDays),
+    ("owner.name", Field::OwnerName),
+    ("title", Field::Title),
+    ("tid", Field::Tid),
+    ("tname", Field::Tname),
+    ("duration", Field::Duration),
+    ("age_days", Field::AgeDays),
+    ("tags", Field::Tags),
+];
+
+impl Field {
+    fn is_number(self) -> bool {
+        matches!(
+            self,
+            Field::OwnerLevel | Field::OwnerFans | Field::OwnerAgeDays | Field::Tid | Field::Duration | Field::AgeDays
+        )
+    }
+
+    fn is_owner(self) -> bool {
+        matches!(self, Field::OwnerLevel | Field::OwnerFans | Field::OwnerAgeDays | Field::OwnerName)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+    Match,
+    NotMatch,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(i64),
+    Text(String),
+    Regex { pattern: String, ignore_case: bool },
+    Op(Op),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+#[derive(Debug, Clone)]
+enum Literal {
+    Number(i64),
+    /// Lowercased
+    Text(String),
+    Regex(Regex),
+}
+
+#[derive(Debug, Clone)]
+struct Comparison {
+    field: Field,
+    op: Op,
+    value: Literal,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Compare(Comparison),
+    Not(Box<Node>),
+    And(Vec<Node>),
+    Or(Vec<Node>),
+}
+
+/// A parsed rule expression
+#[derive(Debug, Clone)]
+pub struct Expr {
+    root: Node,
+}
+
+impl Expr {
+    /// Parses and type checks `source`. Errors name the column they occur at.
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let tokens = tokenize(source)?;
+        if tokens.is_empty() {
+            return Err("the expression is empty".to_string());
+        }
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            end: source.chars().count() + 1,
+            depth: 0,
+        };
+        let root = parser.or()?;
+        if let Some((_, column)) = parser.tokens.get(parser.pos) {
+            return Err(format!("expected '&&', '||' or the end at column {}", column));
+        }
+        Ok(Self { root })
+    }
+
+    /// `now` is the current Unix time, for the ages. False when the result
+    /// is unknown for lack of data.
+    pub fn matches(&self, subject: &Subject, now: i64) -> bool {
+        self.root.eval(subject, now) == Some(true)
+    }
+
+    /// Whether it looks at the uploader's profile
+    pub fn uses_profiles(&self) -> bool {
+        self.root.uses(&Field::is_owner)
+    }
+
+    /// Whether it looks at tags, which take an extra request per video
+    pub fn uses_tags(&self) -> bool {
+        self.root.uses(&|field| field == Field::Tags)
+    }
+}
+
+impl Node {
+    /// `None` when unknown
+    fn eval(&self, subject: &Subject, now: i64) -> Option<bool> {
+        match self {
+            Node::Compare(comparison) => comparison.eval(subject, now),
+            Node::Not(node) => node.eval(subject, now).map(|b| !b),
+            Node::And(nodes) => {
+                let mut result = Some(true);
+                for node in nodes {
+                    match node.eval(subject, now) {
+                        Some(false) => return Some(false),
+                        None => result = None,
+                        Some(true) => {}
+                    }
+                }
+                result
+            }
+            Node::Or(nodes) => {
+                let mut result = Some(false);
+                for node in nodes {
+                    match node.eval(subject, now) {
+                        Some(true) => return Some(true),
+                        None => result = None,
+                        Some(false) => {}
+                    }
+                }
+                result
+            }
+        }
+    }
+
+    fn uses(&self, pred: &impl Fn(Field) -> bool) -> bool {
+        match self {
+            Node::Compare(comparison) => pred(comparison.field),
+            Node::Not(node) => node.uses(pred),
+            Node::And(nodes) | Node::Or(nodes) => nodes.iter().any(|n| n.uses(pred)),
+        }
+    }
+}
+
+impl Comparison {
+    fn eval(&self, subject: &Subject, now: i64) -> Option<bool> {
+        let (profile, video) = (subject.profile, subject.video);
+        let days_since = |time: i64| (now - time) / 86_400;
+        let actual = match self.field {
+            Field::OwnerLevel => profile?.level.map(i64::from),
+            Field::OwnerFans => profile?.fans,
+            Field::OwnerAgeDays => profile?.join_time.map(days_since),
+            Field::Tid => video?.tid.map(i64::from),
+            Field::Duration => video?.duration,
+            Field::AgeDays => video?.pubdate.map(days_since),
+            Field::OwnerName => return self.eval_text(profile?.name.as_deref()?),
+            Field::Title => return self.eval_text(video?.title.as_deref()?),
+            Field::Tname => return self.eval_text(video?.tname.as_deref()?),
+            Field::Tags => {
+                let tags = video?.tags.as_ref()?;
+                let any = tags.iter().any(|tag| self.hits(tag));
+                return Some(any != self.is_negated());
+            }
+        }?;
+        let Literal::Number(value) = self.value else {
+            return None;
+        };
+        Some(match self.op {
+            Op::Lt => actual < value,
+            Op::Le => actual <= value,
+            Op::Gt => actual > value,
+            Op::Ge => actual >= value,
+            Op::Eq => actual == value,
+            Op::Ne => actual != value,
+            Op::Match | Op::NotMatch => return None,
+        })
+    }
+
+    fn is_negated(&self) -> bool {
+        matches!(self.op, Op::Ne | Op::NotMatch)
+    }
+
+    fn eval_text(&self, actual: &str) -> Option<bool> {
+        Some(self.hits(actual) != self.is_negated())
+    }
+
+    /// Whether `actual` is equal to or matches the literal, ignoring negation
+    fn hits(&self, actual: &str) -> bool {
+        match (&self.value, self.op) {
+            (Literal::Regex(regex), _) => regex.is_match(actual),
+            (Literal::Text(value), Op::Eq | Op::Ne) => actual.to_lowercase() == *value,
+            (Literal::Text(value), _) => actual.to_lowercase().contains(value.as_str()),
+            (Literal::Number(_), _) => false,
+        }
+    }
+}
+
+/// Tokens with the column they start at
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, String> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let column = i + 1;
+        let next = chars.get(i + 1).copied();
+        let token = match chars[i] {
+            c if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '&' if next == Some('&') => Token::And,
+            '|' if next == Some('|') => Token::Or,
+            '<' if next == Some('=') => Token::Op(Op::Le),
+            '<' => Token::Op(Op::Lt),
+            '>' if next == Some('=') => Token::Op(Op::Ge),
+            '>' => Token::Op(Op::Gt),
+            '=' if next == Some('=') => Token::Op(Op::Eq),
+            '!' if next == Some('=') => Token::Op(Op::Ne),
+            '!' if next == Some('~') => Token::Op(Op::NotMatch),
+            '!' => Token::Not,
+            '~' => Token::Op(Op::Match),
+            '"' => {
+                let (text, end) = quoted(&chars, i, '"', column)?;
+                i = end;
+                tokens.push((Token::Text(text), column));
+                continue;
+            }
+            '/' => {
+                let (pattern, mut end) = quoted(&chars, i, '/', column)?;
+                let mut ignore_case = false;
+                while let Some(flag) = chars.get(end).filter(|c| c.is_alphanumeric()) {
+                    if *flag != 'i' {
+                        return Err(format!("unknown regex flag '{}' at column {}", flag, end + 1));
+                    }
+                    ignore_case = true;
+                    end += 1;
+                }
+                i = end;
+                tokens.push((Token::Regex { pattern, ignore_case }, column));
+                continue;
+            }
+            c if c.is_ascii_digit() || (c == '-' && next.is_some_and(|n| n.is_ascii_digit())) => {
+                let end = (i + 1..chars.len()).find(|&j| !chars[j].is_ascii_digit()).unwrap_or(chars.len());
+                let digits: String = chars[i..end].iter().collect();
+                let number = digits.parse().map_err(|_| format!("number out of range at column {}", column))?;
+                i = end;
+                tokens.push((Token::Number(number), column));
+                continue;
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let end = (i..chars.len())
+                    .find(|&j| !(chars[j].is_ascii_alphanumeric() || chars[j] == '_' || chars[j] == '.'))
+                    .unwrap_or(chars.len());
+                let name = chars[i..end].iter().collect();
+                i = end;
+                tokens.push((Token::Ident(name), column));
+                continue;
+            }
+            c => return Err(format!("unexpected '{}' at column {}", c, column)),
+        };
+        // All remaining tokens are one or two characters long
+        i += match token {
+            Token::And | Token::Or | Token::Op(Op::Le | Op::Ge | Op::Eq | Op::Ne | Op::NotMatch) => 2,
+            _ => 1,
+        };
+        tokens.push((token, column));
+    }
+    Ok(tokens)
+}
+
+/// Text between the delimiter at `start` and the next unescaped one, and the
+/// index after it. A backslash escapes the delimiter and, in strings, itself;
+/// in regexes other escapes are kept for the regex.
+fn quoted(chars: &[char], start: usize, delimiter: char, column: usize) -> Result<(String, usize), String> {
+    let mut text = String::new();
+    let mut i = start + 1;
+    while let Some(&c) = chars.get(i) {
+        match c {
+            c if c == delimiter => return Ok((text, i + 1)),
+            '\\' if chars.get(i + 1) == Some(&delimiter) || (delimiter == '"' && chars.get(i + 1) == Some(&'\\')) => {
+                text.push(chars[i + 1]);
+                i += 2;
+            }
+            c => {
+                text.push(c);
+                i += 1;
+            }
+        }
+    }
+    Err(format!("unterminated {} starting at column {}", if delimiter == '"' { "string" } else { "regex" }, column))
+}
+
+/// Most `!` and `(` nested inside each other, so parsing and evaluating
+/// cannot run out of stack
+const MAX_DEPTH: usize = 64;
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+    /// Column reported for a missing token at the end
+    end: usize,
+    /// `!` and `(` the parser is currently inside of
+    depth: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<(Token, usize)> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        if self.tokens.get(self.pos).is_some_and(|(t, _)| t == token) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn or(&mut self) -> Result<Node, String> {
+        let mut nodes = vec![self.and()?];
+        while self.eat(&Token::Or) {
+            nodes.push(self.and()?);
+        }
+        Ok(if nodes.len() == 1 { nodes.remove(0) } else { Node::Or(nodes) })
+    }
+
+    fn and(&mut self) -> Result<Node, String> {
+        let mut nodes = vec![self.unary()?];
+        while self.eat(&Token::And) {
+            nodes.push(self.unary()?);
+        }
+        Ok(if nodes.len() == 1 { nodes.remove(0) } else { Node::And(nodes) })
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        match self.next() {
+            Some((Token::Not, column)) => {
+                self.nest(column)?;
+                let node = Node::Not(Box::new(self.unary()?));
+                self.depth -= 1;
+                Ok(node)
+            }
+            Some((Token::Open, column)) => {
+                self.nest(column)?;
+                let node = self.or()?;
+                if !self.eat(&Token::Close) {
+                    return Err(format!("'(' at column {} is not closed", column));
+                }
+                self.depth -= 1;
+                Ok(node)
+            }
+            Some((Token::Ident(name), column)) => self.comparison(&name, column),
+            Some((_, column)) => Err(format!("expected a field, '!' or '(' at column {}", column)),
+            None => Err(format!("expected a field, '!' or '(' at column {}", self.end)),
+        }
+    }
+
+    fn nest(&mut self, column: usize) -> Result<(), String> {
+        if self.depth == MAX_DEPTH {
+            return Err(format!("more than {} levels of '!' and '(' at column {}", MAX_DEPTH, column));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn comparison(&mut self, name: &str, column: usize) -> Result<Node, String> {
+        let field = FIELDS
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, field)| *field)
+            .ok_or_else(|| format!("unknown field '{}' at column {}", name, column))?;
+        let op = match self.next() {
+            Some((Token::Op(op), _)) => op,
+            other => {
+                let column = other.map_or(self.end, |(_, column)| column);
+                return Err(format!("expected a comparison after '{}' at column {}", name, column));
+            }
+        };
+        let (literal, column) = self
+            .next()
+            .ok_or_else(|| format!("expected a value after the comparison at column {}", self.end))?;
+
+        let value = match (field.is_number(), op, literal) {
+            (true, Op::Match | Op::NotMatch, _) => {
+                return Err(format!("'{}' is a number, '~' does not apply at column {}", name, column));
+            }
+            (true, _, Token::Number(n)) => Literal::Number(n),
+            (true, _, _) => return Err(format!("'{}' is compared with a number at column {}", name, column)),
+            (false, Op::Lt | Op::Le | Op::Gt | Op::Ge, _) => {
+                return Err(format!("'{}' is text, only ==, !=, ~ and !~ apply at column {}", name, column));
+            }
+            (false, _, Token::Text(text)) => Literal::Text(text.to_lowercase()),
+            (false, Op::Match | Op::NotMatch, Token::Regex { pattern, ignore_case }) => {
+                let regex = RegexBuilder::new(&pattern)
+                    .case_insensitive(ignore_case)
+                    .build()
+                    .map_err(|e| format!("invalid regex at column {}: {}", column, e))?;
+                Literal::Regex(regex)
+            }
+            (false, _, Token::Regex { .. }) => {
+                return Err(format!("a regex needs '~' or '!~' at column {}", column));
+            }
+            (false, _, _) => return Err(format!("'{}' is compared with a string at column {}", name, column)),
+        };
+        Ok(Node::Compare(Comparison { field, op, value }))
+    }
+}
+
+/// `value` written as a string literal
+pub(crate) fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
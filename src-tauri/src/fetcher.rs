@@ -0,0 +1,688 @@
+use crate::config::{AppConfig, BilibiliCookies};
+use crate::proxy::{parse_proxy, redact, Outcome, ProxyPool, ProxyStat};
+use crate::spider::write_log;
+use crate::wbi::{self, KeyCache};
+use crate::state::SpiderStats;
+use async_trait::async_trait;
+use reqwest::header::{HeaderValue, COOKIE};
+use reqwest::{Client, RequestBuilder};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+pub const API_BASE: &str = "https://api.bilibili.com";
+
+/// Code Bilibili answers with when it rate limits the client IP
+const THROTTLED: i32 = -412;
+
+/// Code for requests without a valid login, e.g. an expired SESSDATA
+const NOT_LOGGED_IN: i32 = -101;
+
+/// Code for a missing or invalid WBI signature
+const WBI_REJECTED: i32 = -352;
+
+const SESSION_CHECK_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What the spider learns about a video
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct VideoMeta {
+    pub owner_mid: i64,
+    pub owner_name: Option<String>,
+    pub title: Option<String>,
+    /// Partition id and name
+    pub tid: Option<i32>,
+    pub tname: Option<String>,
+    /// Seconds
+    pub duration: Option<i64>,
+    /// Publish time, Unix seconds
+    pub pubdate: Option<i64>,
+}
+
+/// What the spider learns about an uploader
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserMeta {
+    pub name: Option<String>,
+    pub face: Option<String>,
+    pub fans: Option<i64>,
+    pub level: Option<i32>,
+    /// Registration time, Unix seconds
+    pub join_time: Option<i64>,
+}
+
+#[derive(Debug, Clone)]
+pub enum FetchError {
+    Network(String),
+    Api(i32),
+    Parse { error: String, body: String },
+    NoOwner,
+    NoWbiKeys,
+    NoUser,
+    /// The fetcher cannot look this kind of thing up
+    Unsupported,
+}
+
+impl FetchError {
+    /// Bilibili gave a definite answer, e.g. the video or user does not
+    /// exist, so asking again soon gets the same one.
+    pub fn is_permanent(&self) -> bool {
+        matches!(self, FetchError::Api(code) if ![THROTTLED, NOT_LOGGED_IN, WBI_REJECTED].contains(code))
+    }
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchError::Network(e) => write!(f, "network error: {}", e),
+            FetchError::Api(code) => write!(f, "API error: code {}", code),
+            FetchError::Parse { error, body } => write!(f, "JSON parse error: {}. Response: {}", error, body),
+            FetchError::NoOwner => write!(f, "no owner in response"),
+            FetchError::NoWbiKeys => write!(f, "no WBI keys in nav response"),
+            FetchError::NoUser => write!(f, "no user in response"),
+            FetchError::Unsupported => write!(f, "not supported by this fetcher"),
+        }
+    }
+}
+
+/// Source of video metadata for the spider.
+///
+/// `stats` is the spider's counters; implementations that talk to the network
+/// record every request they make in it.
+#[async_trait]
+pub trait MetadataFetcher: Send + Sync {
+    async fn fetch(&self, bvid: &str, stats: &SpiderStats) -> Result<VideoMeta, FetchError>;
+
+    /// Resolves a group of BVs, results in input order. Defaults to one
+    /// `fetch` per BV.
+    async fn fetch_many(&self, bvids: &[String], stats: &SpiderStats) -> Vec<Result<VideoMeta, FetchError>> {
+        let mut results = Vec::with_capacity(bvids.len());
+        for bvid in bvids {
+            results.push(self.fetch(bvid, stats).await);
+        }
+        results
+    }
+
+    /// Tag names of a video. Defaults to `Unsupported`.
+    async fn fetch_tags(&self, _bvid: &str, _stats: &SpiderStats) -> Result<Vec<String>, FetchError> {
+        Err(FetchError::Unsupported)
+    }
+
+    /// Looks up an uploader's profile. Defaults to `Unsupported`.
+    async fn fetch_user(&self, _mid: i64, _stats: &SpiderStats) -> Result<UserMeta, FetchError> {
+        Err(FetchError::Unsupported)
+    }
+
+    /// Called with the current config before work is dispatched.
+    fn apply_config(&self, _config: &AppConfig) {}
+
+    /// Called periodically by the spider for background upkeep.
+    async fn maintain(&self, _stats: &SpiderStats) {}
+}
+
+/// Responses carrying Bilibili's top-level status code
+pub trait ApiCode {
+    fn code(&self) -> i32;
+}
+
+#[derive(Deserialize, Debug)]
+struct BilibiliApiResponse {
+    code: i32,
+    data: Option<BilibiliApiData>,
+}
+
+#[derive(Deserialize, Debug)]
+struct BilibiliApiData {
+    title: Option<String>,
+    owner: Option<BilibiliOwner>,
+    tid: Option<i32>,
+    tname: Option<String>,
+    duration: Option<i64>,
+    pubdate: Option<i64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct BilibiliOwner {
+    mid: i64,
+    name: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct BilibiliCardsResponse {
+    code: i32,
+    data: Option<HashMap<String, BilibiliApiData>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct BilibiliNavResponse {
+    code: i32,
+    // Present even when not logged in
+    data: Option<BilibiliNavData>,
+}
+
+#[derive(Deserialize, Debug)]
+struct BilibiliNavData {
+    wbi_img: Option<BilibiliWbiImg>,
+}
+
+#[derive(Deserialize, Debug)]
+struct BilibiliWbiImg {
+    img_url: String,
+    sub_url: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct BilibiliAccInfoResponse {
+    code: i32,
+    data: Option<BilibiliAccInfo>,
+}
+
+#[derive(Deserialize, Debug)]
+struct BilibiliAccInfo {
+    name: Option<String>,
+    face: Option<String>,
+    level: Option<i32>,
+    /// 0 when Bilibili does not disclose it
+    #[serde(default)]
+    jointime: i64,
+}
+
+#[derive(Deserialize, Debug)]
+struct BilibiliTagsResponse {
+    code: i32,
+    data: Option<Vec<BilibiliTag>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct BilibiliTag {
+    tag_name: String,
+}
+
+impl ApiCode for BilibiliTagsResponse {
+    fn code(&self) -> i32 {
+        self.code
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct BilibiliRelationStatResponse {
+    code: i32,
+    data: Option<BilibiliRelationStat>,
+}
+
+#[derive(Deserialize, Debug)]
+struct BilibiliRelationStat {
+    follower: i64,
+}
+
+impl ApiCode for BilibiliAccInfoResponse {
+    fn code(&self) -> i32 {
+        self.code
+    }
+}
+
+impl ApiCode for BilibiliRelationStatResponse {
+    fn code(&self) -> i32 {
+        self.code
+    }
+}
+
+impl ApiCode for BilibiliNavResponse {
+    fn code(&self) -> i32 {
+        self.code
+    }
+}
+
+impl ApiCode for BilibiliApiResponse {
+    fn code(&self) -> i32 {
+        self.code
+    }
+}
+
+impl ApiCode for BilibiliCardsResponse {
+    fn code(&self) -> i32 {
+        self.code
+    }
+}
+
+impl BilibiliApiData {
+    fn into_meta(self) -> Option<VideoMeta> {
+        let owner = self.owner?;
+        Some(VideoMeta {
+            owner_mid: owner.mid,
+            owner_name: owner.name,
+            title: self.title,
+            tid: self.tid,
+            tname: self.tname,
+            duration: self.duration,
+            pubdate: self.pubdate,
+        })
+    }
+}
+
+/// The real Bilibili web API.
+pub struct BilibiliFetcher {
+    base_url: String,
+    client: RwLock<ClientState>,
+    session: Mutex<Session>,
+    wbi_keys: KeyCache,
+}
+
+/// Cookies sent with every request and when they were last verified
+struct Session {
+    cookies: BilibiliCookies,
+    header: Option<HeaderValue>,
+    checked_at: Option<Instant>,
+}
+
+impl Session {
+    fn new(cookies: BilibiliCookies) -> Self {
+        let header = cookies.header().and_then(|value| match HeaderValue::from_str(&value) {
+            Ok(header) => Some(header),
+            Err(_) => {
+                write_log("Cookies contain characters not allowed in a header, not sending them");
+                None
+            }
+        });
+        Self {
+            cookies,
+            header,
+            checked_at: None,
+        }
+    }
+}
+
+struct ClientState {
+    /// Used when no proxy is configured
+    direct: Client,
+    pool: Arc<ProxyPool>,
+    proxies: Vec<String>,
+}
+
+impl ClientState {
+    fn new(proxies: Vec<String>) -> Self {
+        let clients = proxies
+            .iter()
+            .filter_map(|url| match build_client(Some(url)) {
+                Ok(client) => {
+                    write_log(&format!("Proxy added: {}", redact(url)));
+                    Some((url.clone(), client))
+                }
+                Err(e) => {
+                    write_log(&e);
+                    None
+                }
+            })
+            .collect();
+
+        Self {
+            direct: build_client(None).unwrap_or_else(|e| {
+                write_log(&format!("Failed to build client: {}", e));
+                Client::new()
+            }),
+            pool: Arc::new(ProxyPool::new(clients)),
+            proxies,
+        }
+    }
+}
+
+impl BilibiliFetcher {
+    pub fn new(base_url: &str, config: &AppConfig) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            client: RwLock::new(ClientState::new(config.proxies())),
+            session: Mutex::new(Session::new(config.cookies.clone())),
+            wbi_keys: KeyCache::default(),
+        }
+    }
+
+    /// A GET through `client` carrying the configured cookies.
+    fn get(&self, client: &Client, url: &str) -> RequestBuilder {
+        let request = client.get(url);
+        match &self.session.lock().unwrap().header {
+            Some(cookie) => request.header(COOKIE, cookie.clone()),
+            None => request,
+        }
+    }
+
+    /// Asks the nav endpoint whether the configured SESSDATA is still logged
+    /// in, at most once per `SESSION_CHECK_INTERVAL`.
+    async fn check_session(&self, stats: &SpiderStats) {
+        {
+            let mut session = self.session.lock().unwrap();
+            let recently_checked = session.checked_at.is_some_and(|at| at.elapsed() < SESSION_CHECK_INTERVAL);
+            if !session.cookies.has_session() || recently_checked {
+                return;
+            }
+            session.checked_at = Some(Instant::now());
+        }
+
+        let client = {
+            let state = self.client.read().unwrap();
+            state.pool.pick().map(|(_, client)| client).unwrap_or_else(|| state.direct.clone())
+        };
+        let url = format!("{}/x/web-interface/nav", self.base_url);
+        let resp = match self.get(&client, &url).timeout(PROBE_TIMEOUT).send().await {
+            Ok(resp) => resp,
+            Err(e) => return write_log(&format!("Session check failed: {}", describe(&e))),
+        };
+        match resp.json::<BilibiliNavResponse>().await {
+            Ok(json) => {
+                let expired = json.code == NOT_LOGGED_IN;
+                if expired && !stats.session_expired.load(Ordering::Relaxed) {
+                    write_log("Bilibili session expired, update SESSDATA");
+                }
+                stats.session_expired.store(expired, Ordering::Relaxed);
+            }
+            Err(e) => write_log(&format!("Session check failed: {}", describe(&e))),
+        }
+    }
+
+    /// Per-proxy health and counters, empty when no proxy is configured.
+    pub fn proxy_stats(&self) -> Vec<ProxyStat> {
+        self.client.read().unwrap().pool.stats()
+    }
+
+    /// GETs a WBI endpoint, e.g. `/x/space/wbi/acc/info`, with signed
+    /// `params`. A rejected signature refreshes the keys and retries once.
+    /// The spider only reaches it through [`MetadataFetcher::fetch_user`];
+    /// other signed endpoints are not wired up yet.
+    pub async fn get_signed<T: DeserializeOwned + ApiCode>(
+        &self,
+        path: &str,
+        params: &[(&str, String)],
+        stats: &SpiderStats,
+    ) -> Result<T, FetchError> {
+        let mut retried = false;
+        loop {
+            let mixin_key = self.wbi_mixin_key(stats).await?;
+            let url = format!("{}{}?{}", self.base_url, path, wbi::sign(params, &mixin_key, wbi::now()));
+            let json: T = self.get_json(&url, stats).await?;
+
+            if json.code() == WBI_REJECTED && !retried {
+                write_log("WBI signature rejected, refreshing keys");
+                self.wbi_keys.invalidate();
+                retried = true;
+                continue;
+            }
+            return Ok(json);
+        }
+    }
+
+    async fn wbi_mixin_key(&self, stats: &SpiderStats) -> Result<String, FetchError> {
+        if let Some(key) = self.wbi_keys.get() {
+            return Ok(key);
+        }
+
+        let url = format!("{}/x/web-interface/nav", self.base_url);
+        let json: BilibiliNavResponse = self.get_json(&url, stats).await?;
+        let img = json.data.and_then(|data| data.wbi_img).ok_or(FetchError::NoWbiKeys)?;
+        let (img_key, sub_key) = wbi::key_from_url(&img.img_url)
+            .zip(wbi::key_from_url(&img.sub_url))
+            .ok_or(FetchError::NoWbiKeys)?;
+
+        let mixin_key = wbi::mixin_key(&img_key, &sub_key);
+        self.wbi_keys.set(mixin_key.clone());
+        Ok(mixin_key)
+    }
+
+    /// Resolves many BVs with one request to the article card endpoint, which
+    /// also returns video cards keyed by BV.
+    pub async fn fetch_batch(&self, bvids: &[String], stats: &SpiderStats) -> Result<HashMap<String, VideoMeta>, FetchError> {
+        let url = format!("{}/x/article/cards?ids={}", self.base_url, bvids.join(","));
+        let json: BilibiliCardsResponse = self.get_json(&url, stats).await?;
+
+        if json.code != 0 {
+            return Err(FetchError::Api(json.code));
+        }
+        Ok(json
+            .data
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(bvid, card)| card.into_meta().map(|meta| (bvid, meta)))
+            .collect())
+    }
+
+    /// GETs `url` through the next proxy in rotation and parses the body as
+    /// JSON. The request is counted in `stats` and its outcome recorded
+    /// against the proxy.
+    async fn get_json<T: DeserializeOwned + ApiCode>(&self, url: &str, stats: &SpiderStats) -> Result<T, FetchError> {
+        let (pool, route, direct) = {
+            let state = self.client.read().unwrap();
+            (state.pool.clone(), state.pool.pick(), state.direct.clone())
+        };
+        let client = route.as_ref().map(|(_, client)| client).unwrap_or(&direct);
+
+        stats.actual_api_req_count.fetch_add(1, Ordering::Relaxed);
+        let start_time = Instant::now();
+
+        let result = async {
+            let resp = self.get(client, url).send().await.map_err(|e| FetchError::Network(e.to_string()))?;
+            if resp.status().as_u16() == 412 {
+                return Err(FetchError::Api(THROTTLED));
+            }
+            // 获取响应文本用于日志记录
+            let text = resp.text().await.map_err(|e| FetchError::Network(e.to_string()))?;
+            // 解析JSON
+            serde_json::from_str::<T>(&text).map_err(|e| FetchError::Parse {
+                error: e.to_string(),
+                body: text,
+            })
+        }
+        .await;
+
+        let duration = start_time.elapsed().as_millis() as u64;
+        stats.req_time_sum.fetch_add(duration, Ordering::Relaxed);
+
+        let rejected = matches!(&result, Ok(json) if json.code() == NOT_LOGGED_IN);
+        if rejected && self.session.lock().unwrap().cookies.has_session() && !stats.session_expired.swap(true, Ordering::Relaxed) {
+            write_log("Bilibili session expired, update SESSDATA");
+        }
+
+        if let Some((index, _)) = route {
+            let outcome = match &result {
+                Ok(json) if json.code() == THROTTLED => Outcome::Throttled,
+                Ok(_) => Outcome::Success { latency_ms: duration },
+                Err(FetchError::Api(THROTTLED)) => Outcome::Throttled,
+                Err(_) => Outcome::Failure,
+            };
+            pool.report(index, outcome);
+            *stats.proxies.lock().unwrap() = pool.stats();
+        }
+        result
+    }
+}
+
+#[async_trait]
+impl MetadataFetcher for BilibiliFetcher {
+    /// Resolves one BV through the video view endpoint.
+    async fn fetch(&self, bvid: &str, stats: &SpiderStats) -> Result<VideoMeta, FetchError> {
+        let url = format!("{}/x/web-interface/view?bvid={}", self.base_url, bvid);
+        let json: BilibiliApiResponse = self.get_json(&url, stats).await?;
+
+        if json.code != 0 {
+            // Logic for known API errors (e.g., -404)
+            return Err(FetchError::Api(json.code));
+        }
+        json.data.and_then(BilibiliApiData::into_meta).ok_or(FetchError::NoOwner)
+    }
+
+    /// Batch lookup with a per-BV fallback for anything the batch request did
+    /// not resolve, or when it got an answer that was no use. When it was
+    /// throttled or never reached Bilibili, one request per BV would only
+    /// make things worse, so every BV fails with that error instead. They
+    /// leave the pending set and are queued again the next time a page
+    /// shows them.
+    async fn fetch_many(&self, bvids: &[String], stats: &SpiderStats) -> Vec<Result<VideoMeta, FetchError>> {
+        if bvids.len() == 1 {
+            return vec![self.fetch(&bvids[0], stats).await];
+        }
+
+        let mut resolved = match self.fetch_batch(bvids, stats).await {
+            Ok(resolved) => resolved,
+            Err(e @ (FetchError::Network(_) | FetchError::Api(THROTTLED))) => {
+                write_log(&format!("Batch request for {} BVs failed: {}", bvids.len(), e));
+                return vec![Err(e); bvids.len()];
+            }
+            Err(e) => {
+                write_log(&format!("Batch request for {} BVs failed, falling back: {}", bvids.len(), e));
+                HashMap::new()
+            }
+        };
+
+        let mut results = Vec::with_capacity(bvids.len());
+        for bvid in bvids {
+            match resolved.remove(bvid) {
+                Some(meta) => results.push(Ok(meta)),
+                None => results.push(self.fetch(bvid, stats).await),
+            }
+        }
+        results
+    }
+
+    async fn fetch_tags(&self, bvid: &str, stats: &SpiderStats) -> Result<Vec<String>, FetchError> {
+        let url = format!("{}/x/tag/archive/tags?bvid={}", self.base_url, bvid);
+        let json: BilibiliTagsResponse = self.get_json(&url, stats).await?;
+        if json.code != 0 {
+            return Err(FetchError::Api(json.code));
+        }
+        Ok(json.data.unwrap_or_default().into_iter().map(|tag| tag.tag_name).collect())
+    }
+
+    /// Name, avatar and level from the WBI-signed space endpoint, followers
+    /// from the relation endpoint.
+    async fn fetch_user(&self, mid: i64, stats: &SpiderStats) -> Result<UserMeta, FetchError> {
+        let params = [("mid", mid.to_string())];
+        let json: BilibiliAccInfoResponse = self.get_signed("/x/space/wbi/acc/info", &params, stats).await?;
+        if json.code != 0 {
+            return Err(FetchError::Api(json.code));
+        }
+        let info = json.data.ok_or(FetchError::NoUser)?;
+
+        // A profile without the follower count is still worth keeping
+        let url = format!("{}/x/relation/stat?vmid={}", self.base_url, mid);
+        let fans = match self.get_json::<BilibiliRelationStatResponse>(&url, stats).await {
+            Ok(json) if json.code == 0 => json.data.map(|stat| stat.follower),
+            Ok(json) => {
+                write_log(&format!("Failed to get followers of {}: {}", mid, FetchError::Api(json.code)));
+                None
+            }
+            Err(e) => {
+                write_log(&format!("Failed to get followers of {}: {}", mid, e));
+                None
+            }
+        };
+
+        Ok(UserMeta {
+            name: info.name,
+            face: info.face,
+            fans,
+            level: info.level,
+            join_time: Some(info.jointime).filter(|&t| t > 0),
+        })
+    }
+
+    fn apply_config(&self, config: &AppConfig) {
+        let proxies = config.proxies();
+        let mut state = self.client.write().unwrap();
+        if proxies != state.proxies {
+            write_log("Proxy config changed. Rebuilding clients...");
+            *state = ClientState::new(proxies);
+        }
+
+        let mut session = self.session.lock().unwrap();
+        if config.cookies != session.cookies {
+            write_log("Cookies changed");
+            *session = Session::new(config.cookies.clone());
+        }
+    }
+
+    /// Probes proxies taken out of rotation and puts back the ones that
+    /// answer without being throttled, then verifies the login session.
+    async fn maintain(&self, stats: &SpiderStats) {
+        let (pool, unhealthy) = {
+            let state = self.client.read().unwrap();
+            (state.pool.clone(), state.pool.unhealthy())
+        };
+
+        for (index, proxy, client) in unhealthy {
+            match probe(&client, &self.base_url).await {
+                Ok(_) => {
+                    write_log(&format!("Proxy {} is reachable again", redact(&proxy)));
+                    pool.mark_healthy(index);
+                }
+                Err(e) => write_log(&format!("Proxy {} still unhealthy: {}", redact(&proxy), e)),
+            }
+        }
+        *stats.proxies.lock().unwrap() = pool.stats();
+
+        self.check_session(stats).await;
+    }
+}
+
+/// Sends one request to the nav endpoint through `proxy_url`, which answers
+/// even without a login. Returns the latency in milliseconds, or a readable
+/// reason the proxy cannot be used.
+pub async fn test_proxy(base_url: &str, proxy_url: &str) -> Result<u64, String> {
+    let client = build_client(Some(proxy_url))?;
+    probe(&client, base_url.trim_end_matches('/')).await
+}
+
+async fn probe(client: &Client, base_url: &str) -> Result<u64, String> {
+    let start_time = Instant::now();
+    let resp = client
+        .get(format!("{}/x/web-interface/nav", base_url))
+        .timeout(PROBE_TIMEOUT)
+        .send()
+        .await
+        .map_err(|e| describe(&e))?;
+
+    let status = resp.status();
+    if status.as_u16() == 412 {
+        return Err("throttled by Bilibili (HTTP 412)".to_string());
+    }
+    let text = resp.text().await.map_err(|e| describe(&e))?;
+    let json: BilibiliNavResponse = serde_json::from_str(&text).map_err(|_| {
+        let body: String = text.chars().take(200).collect();
+        format!("unexpected response (HTTP {}): {}", status, body)
+    })?;
+    if json.code == THROTTLED {
+        return Err("throttled by Bilibili (code -412)".to_string());
+    }
+    Ok(start_time.elapsed().as_millis() as u64)
+}
+
+/// reqwest's message alone hides the cause, e.g. "connection refused" or a
+/// failed SOCKS handshake, so append the whole source chain.
+/// Whether `s` looks like a BV id: "BV" and letters or digits. Only those
+/// go into request URLs unescaped.
+pub fn is_bvid(s: &str) -> bool {
+    s.len() > 2 && s.len() <= 16 && s.starts_with("BV") && s.bytes().all(|b| b.is_ascii_alphanumeric())
+}
+
+fn describe(e: &reqwest::Error) -> String {
+    let mut message = e.to_string();
+    let mut source = std::error::Error::source(e);
+    while let Some(cause) = source {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    message
+}
+
+fn build_client(proxy_url: Option<&str>) -> Result<Client, String> {
+    let mut builder = Client::builder()
+        .user_agent("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36 Edg/120.0.0.0")
+        .pool_idle_timeout(std::time::Duration::from_secs(15))
+        .pool_max_idle_per_host(16);
+
+    if let Some(url) = proxy_url {
+        builder = builder.proxy(parse_proxy(url)?);
+    }
+    builder.build().map_err(|e| describe(&e))
+}
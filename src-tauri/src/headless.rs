@@ -0,0 +1,58 @@
+use crate::cleaner;
+use crate::fetcher::{BilibiliFetcher, API_BASE};
+use crate::paths::AppPaths;
+use crate::server;
+use crate::spider;
+use crate::state;
+use std::future::Future;
+use std::net::ToSocketAddrs;
+use std::sync::Arc;
+
+/// Runs the HTTP service, spider and cache cleaner without the Tauri window.
+/// Blocks until SIGINT/SIGTERM is received.
+pub fn run() {
+    let paths = AppPaths::standard();
+    spider::set_log_dir(paths.log_dir.clone());
+    run_until(&paths, server::DEFAULT_ADDR, wait_for_signal());
+}
+
+/// Runs the headless service on `addr` with the data in `paths` until `shutdown` completes.
+pub fn run_until<A, F>(paths: &AppPaths, addr: A, shutdown: F)
+where
+    A: ToSocketAddrs + Copy + std::fmt::Debug,
+    F: Future<Output = ()>,
+{
+    let (app_state, config_manager) = state::init(paths);
+    println!("Using data directory {}", paths.data_dir.display());
+
+    // Bind before entering the runtime so the blocking bind never stalls a runtime thread.
+    server::run_server_on(app_state.clone(), addr);
+
+    let runtime = tokio::runtime::Runtime::new().expect("Failed to start tokio runtime");
+    runtime.block_on(async move {
+        let tasks = &app_state.tasks;
+        let fetcher = Arc::new(BilibiliFetcher::new(API_BASE, &config_manager.get_config()));
+        tokio::spawn(tasks.track_future(spider::start_spider(app_state.clone(), config_manager.clone(), fetcher)));
+        tokio::spawn(tasks.track_future(cleaner::start_cleaner(app_state.clone(), config_manager.clone())));
+
+        shutdown.await;
+        println!("Shutting down...");
+        app_state.shutdown().await;
+    });
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigterm = signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = sigterm.recv() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
@@ -0,0 +1,155 @@
+use rusqlite::{Connection, Result};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Mutex, RwLock};
+
+use crate::db;
+
+/// In-memory copy of the blocklist plus a bounded BV → mid cache, so the
+/// check endpoints can answer without touching SQLite. Temporary blocks
+/// stop counting once they expire, before the cleaner purges them, and
+/// blocks in disabled lists do not count at all.
+///
+/// The database stays the source of truth: the set is loaded from it on
+/// startup and every successful write the app makes is mirrored here.
+/// Writers outside the app, like the blocklist CLI, have the running server
+/// call `reload` through `/reload`; until then the app does not see them.
+pub struct BlockIndex {
+    blocked: RwLock<HashMap<i64, Block>>,
+    disabled_lists: RwLock<HashSet<i64>>,
+    bv_mids: Mutex<LruMap>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Block {
+    list_id: i64,
+    /// End of a temporary block
+    expires_at: Option<i64>,
+}
+
+impl BlockIndex {
+    pub fn load(conn: &Connection, bv_capacity: usize) -> Result<Self> {
+        let index = Self {
+            blocked: RwLock::new(HashMap::new()),
+            disabled_lists: RwLock::new(HashSet::new()),
+            bv_mids: Mutex::new(LruMap::new(bv_capacity)),
+        };
+        index.reload(conn)?;
+        Ok(index)
+    }
+
+    /// Reads the blocklist and block lists again, e.g. after another process
+    /// changed them. The BV cache is kept, it does not depend on them.
+    pub fn reload(&self, conn: &Connection) -> Result<()> {
+        let blocked = db::list_users(conn)?
+            .into_iter()
+            .map(|u| {
+                let block = Block {
+                    list_id: u.list_id,
+                    expires_at: u.expires_at,
+                };
+                (u.mid, block)
+            })
+            .collect();
+        let disabled_lists = db::list_block_lists(conn)?.into_iter().filter(|l| !l.enabled).map(|l| l.id).collect();
+        *self.blocked.write().unwrap() = blocked;
+        *self.disabled_lists.write().unwrap() = disabled_lists;
+        Ok(())
+    }
+
+    pub fn is_blocked(&self, mid: i64) -> bool {
+        let Some(block) = self.blocked.read().unwrap().get(&mid).copied() else {
+            return false;
+        };
+        block.expires_at.is_none_or(|t| t > chrono::Utc::now().timestamp())
+            && !self.disabled_lists.read().unwrap().contains(&block.list_id)
+    }
+
+    /// Blocks `mid` for good in the default list
+    pub fn add_blocked(&self, mid: i64) {
+        self.add_block(mid, db::DEFAULT_LIST, None);
+    }
+
+    /// `expires_at` in Unix seconds, `None` for good
+    pub fn add_block(&self, mid: i64, list_id: i64, expires_at: Option<i64>) {
+        self.blocked.write().unwrap().insert(mid, Block { list_id, expires_at });
+    }
+
+    pub fn set_list_enabled(&self, list_id: i64, enabled: bool) {
+        let mut disabled = self.disabled_lists.write().unwrap();
+        if enabled {
+            disabled.remove(&list_id);
+        } else {
+            disabled.insert(list_id);
+        }
+    }
+
+    pub fn remove_blocked(&self, mid: i64) {
+        self.blocked.write().unwrap().remove(&mid);
+    }
+
+    pub fn get_mid_by_bv(&self, bvid: &str) -> Option<i64> {
+        self.bv_mids.lock().unwrap().get(bvid)
+    }
+
+    pub fn cache_bv_mid(&self, bvid: &str, mid: i64) {
+        self.bv_mids.lock().unwrap().insert(bvid, mid);
+    }
+
+    /// Drops cached BVs, e.g. after expired rows were deleted from bv_cache.
+    pub fn clear_bvs(&self) {
+        self.bv_mids.lock().unwrap().clear();
+    }
+}
+
+/// Minimal LRU map: `order` maps a monotonically increasing tick to the key
+/// used at that tick, so the first entry is always the least recently used.
+struct LruMap {
+    map: HashMap<String, (i64, u64)>,
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    capacity: usize,
+}
+
+impl LruMap {
+    fn new(capacity: usize) -> Self {
+        Self {
+            map: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            capacity: capacity.max(1),
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<i64> {
+        self.tick += 1;
+        let (value, last_used) = self.map.get_mut(key)?;
+        let key = self.order.remove(last_used).unwrap();
+        *last_used = self.tick;
+        self.order.insert(self.tick, key);
+        Some(*value)
+    }
+
+    fn insert(&mut self, key: &str, value: i64) {
+        self.tick += 1;
+        if let Some((old_value, last_used)) = self.map.get_mut(key) {
+            let key = self.order.remove(last_used).unwrap();
+            *old_value = value;
+            *last_used = self.tick;
+            self.order.insert(self.tick, key);
+            return;
+        }
+
+        if self.map.len() >= self.capacity {
+            if let Some((_, oldest)) = self.order.pop_first() {
+                self.map.remove(&oldest);
+            }
+        }
+        self.map.insert(key.to_string(), (value, self.tick));
+        self.order.insert(self.tick, key.to_string());
+    }
+
+    fn clear(&mut self) {
+        self.map.clear();
+        self.order.clear();
+    }
+}
@@ -1,16 +1,33 @@
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use tauri::{Manager, State};
-
-mod config;
-mod db;
-mod server;
-mod spider;
-mod state;
+use tauri::{Manager, RunEvent, State};
+
+mod cleaner;
+pub mod config;
+pub mod db;
+pub mod expr;
+pub mod fetcher;
+pub mod headless;
+mod index;
+pub mod paths;
+pub mod pool;
+pub mod proxy;
+pub mod queue;
+pub mod rules;
+pub mod server;
+pub mod spider;
+pub mod state;
+pub mod wbi;
 
 use config::{AppConfig, ConfigManager};
+use fetcher::{BilibiliFetcher, API_BASE};
+use paths::AppPaths;
+use proxy::ProxyStat;
+use rules::Rule;
 use state::AppState;
 
+pub use headless::run as run_headless;
+
 #[derive(serde::Serialize)]
 struct FrontendStats {
     service_req_count: usize,
@@ -26,6 +43,11 @@ struct FrontendStats {
     spider_total_received: usize,
     spider_actual_reqs: usize,
     server_status: i8, // 0: Init, 1: Running, 2: Failed
+    proxies: Vec<ProxyStat>,
+    session_expired: bool,
+    spider_overflow_dropped: usize,
+    spider_overflow_rejected: usize,
+    spider_skipped_count: usize,
 }
 
 #[tauri::command]
@@ -50,6 +72,11 @@ fn get_stats(state: State<Arc<AppState>>) -> FrontendStats {
         spider_total_received: state.spider_stats.total_received_count.load(Ordering::Relaxed),
         spider_actual_reqs: state.spider_stats.actual_api_req_count.load(Ordering::Relaxed),
         server_status: state.server_status.load(Ordering::Relaxed),
+        proxies: state.spider_stats.proxies.lock().unwrap().clone(),
+        session_expired: state.spider_stats.session_expired.load(Ordering::Relaxed),
+        spider_overflow_dropped: state.spider_stats.overflow_dropped.load(Ordering::Relaxed),
+        spider_overflow_rejected: state.spider_stats.overflow_rejected.load(Ordering::Relaxed),
+        spider_skipped_count: state.spider_stats.skipped_count.load(Ordering::Relaxed),
     }
 }
 
@@ -59,8 +86,82 @@ fn get_app_config(state: State<Arc<ConfigManager>>) -> AppConfig {
 }
 
 #[tauri::command]
-fn set_app_config(state: State<Arc<ConfigManager>>, config: AppConfig) -> Result<(), String> {
-    state.set_config(config)
+fn set_app_config(
+    state: State<Arc<ConfigManager>>,
+    app_state: State<Arc<AppState>>,
+    config: AppConfig,
+) -> Result<(), String> {
+    for url in config.proxies() {
+        proxy::parse_proxy(&url)?;
+    }
+    // New cookies get a fresh check instead of showing the old verdict
+    let cookies_changed = config.cookies != state.get_config().cookies;
+    let overflow = config.queue_overflow;
+    state.set_config(config)?;
+    app_state.spider_queue.set_overflow_policy(overflow);
+    if cookies_changed {
+        app_state.spider_stats.session_expired.store(false, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+#[derive(serde::Serialize)]
+struct ProxyTestResult {
+    latency_ms: u64,
+}
+
+/// Probes Bilibili through `proxy_url` without saving it
+#[tauri::command]
+async fn test_proxy(proxy_url: String) -> Result<ProxyTestResult, String> {
+    let latency_ms = fetcher::test_proxy(API_BASE, &proxy_url).await?;
+    Ok(ProxyTestResult { latency_ms })
+}
+
+/// Cached profile of `mid`; a missing or stale one is fetched in the background
+#[tauri::command]
+async fn get_user_profile(state: State<'_, Arc<AppState>>, mid: i64) -> Result<spider::ProfileLookup, String> {
+    spider::lookup_profile(&state, mid).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn list_rules(state: State<Arc<AppState>>) -> Vec<Rule> {
+    state.rules.list()
+}
+
+#[tauri::command]
+async fn save_rule(state: State<'_, Arc<AppState>>, rule: Rule) -> Result<Rule, String> {
+    rules::save_rule(&state, rule).await
+}
+
+#[tauri::command]
+async fn delete_rule(state: State<'_, Arc<AppState>>, id: i64) -> Result<bool, String> {
+    rules::delete_rule(&state, id).await
+}
+
+#[tauri::command]
+async fn list_block_lists(state: State<'_, Arc<AppState>>) -> Result<Vec<db::BlockList>, String> {
+    let conn = state.db.read().await;
+    db::list_block_lists(&conn).map_err(|e| e.to_string())
+}
+
+/// Temporary blocks that ran out, most recently ended first
+#[tauri::command]
+async fn list_block_history(state: State<'_, Arc<AppState>>, limit: usize) -> Result<Vec<db::BlockHistoryEntry>, String> {
+    let conn = state.db.read().await;
+    db::list_block_history(&conn, limit).map_err(|e| e.to_string())
+}
+
+/// Switches a block list on or off; false if there is no such list
+#[tauri::command]
+async fn set_block_list_enabled(state: State<'_, Arc<AppState>>, name: String, enabled: bool) -> Result<bool, String> {
+    server::set_block_list_enabled(&state, &name, enabled).await.map_err(|e| e.to_string())
+}
+
+/// Checks the syntax of a rule expression, or a list of uploaders, and what
+/// it would hide among the cached videos
+#[tauri::command]
+async fn dry_run(state: State<'_, Arc<AppState>>, candidate: rules::Candidate) -> Result<rules::DryRun, String> {
+    rules::dry_run(&state, &candidate).await
 }
 
 #[tauri::command]
@@ -70,6 +171,11 @@ fn toggle_spider_status(state: State<Arc<AppState>>) -> bool {
     !current
 }
 
+#[tauri::command]
+async fn restart_server(state: State<'_, Arc<AppState>>) -> Result<(), String> {
+    server::restart_server(state.inner().clone()).await.map_err(|e| e.to_string())
+}
+
 #[tauri::command]
 fn set_always_on_top(window: tauri::Window, always_on_top: bool) -> Result<(), String> {
     window.set_always_on_top(always_on_top).map_err(|e| e.to_string())
@@ -77,77 +183,48 @@ fn set_always_on_top(window: tauri::Window, always_on_top: bool) -> Result<(), S
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
-    // Initialize DB
-    let db_path = "./blocked_users.db";
-    let conn = db::init_db(db_path).expect("Failed to init DB");
-    
-    // Initialize Config
-    let config_manager = Arc::new(ConfigManager::new("./config.json"));
-
-    // Initial stats load
-    let blocked_count = db::get_blocked_count(&conn).unwrap_or(0);
-    let cache_count = db::get_bv_cache_count(&conn).unwrap_or(0);
-
-    let (tx, rx) = tokio::sync::mpsc::channel(1000);
-    let app_state = Arc::new(AppState::new(conn, tx));
-    
-    app_state.db_stats.blocked_user_count.store(blocked_count, Ordering::Relaxed);
-    app_state.spider_stats.bv_cache_count.store(cache_count, Ordering::Relaxed);
-
-    let spider_state = app_state.clone();
-    let server_state = app_state.clone();
-    let cleaner_state = app_state.clone();
-    let spider_config = config_manager.clone();
-    let cleaner_config = config_manager.clone();
-
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
-        .manage(app_state)
-        .manage(config_manager)
-        .setup(move |app| {
+        .setup(|app| {
              if let Some(window) = app.get_webview_window("main") {
                  let _ = window.eval("document.addEventListener('contextmenu', e => e.preventDefault());");
              }
 
+             let paths = AppPaths::resolve(app.path().app_data_dir()?, app.path().app_log_dir()?);
+             spider::set_log_dir(paths.log_dir.clone());
+             let (app_state, config_manager) = state::init(&paths);
+
+             app.manage(app_state.clone());
+             app.manage(config_manager.clone());
+
+             let spider_state = app_state.clone();
+             let server_state = app_state.clone();
+             let cleaner_state = app_state.clone();
+             let spider_config = config_manager.clone();
+             let cleaner_config = config_manager.clone();
+
              // Spawn Spider
-             tauri::async_runtime::spawn(async move {
-                 spider::start_spider(spider_state, rx, spider_config).await;
-             });
+             let fetcher = Arc::new(BilibiliFetcher::new(API_BASE, &config_manager.get_config()));
+             tauri::async_runtime::spawn(app_state.tasks.track_future(async move {
+                 spider::start_spider(spider_state, spider_config, fetcher).await;
+             }));
 
              // Spawn Cleaner
-             tauri::async_runtime::spawn(async move {
-                // Initial sleep to let app startup
-                tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
-                
-                loop {
-                    let days = cleaner_config.get_config().cache_expiration_days;
-                    if days > 0 {
-                        let secs = (days * 24 * 3600) as i64;
-                        // Use a block to drop the lock after operation
-                        {
-                            let conn = cleaner_state.db_conn.lock().await;
-                            if let Ok(deleted) = db::clean_expired_cache(&*conn, secs) {
-                                cleaner_state.spider_stats.session_cleaned_count.fetch_add(deleted, Ordering::Relaxed);
-                                // Update stats
-                                if let Ok(count) = db::get_bv_cache_count(&*conn) {
-                                    cleaner_state.spider_stats.bv_cache_count.store(count, Ordering::Relaxed);
-                                }
-                            } else {
-                                eprintln!("Failed to clean cache");
-                            }
-                        }
-                    }
-                    // Check every hour
-                    tokio::time::sleep(tokio::time::Duration::from_secs(3600)).await;
-                }
-             });
+             tauri::async_runtime::spawn(app_state.tasks.track_future(cleaner::start_cleaner(cleaner_state, cleaner_config)));
 
-             // Start Server (it spawns its own thread)
+             // Start Server (it spawns its own thread and reports the bind result)
              server::run_server(server_state);
 
             Ok(())
         })
-        .invoke_handler(tauri::generate_handler![get_stats, get_app_config, set_app_config, toggle_spider_status, set_always_on_top])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .invoke_handler(tauri::generate_handler![get_stats, get_app_config, set_app_config, toggle_spider_status, set_always_on_top, restart_server, test_proxy, get_user_profile, list_rules, save_rule, delete_rule, dry_run, list_block_lists, list_block_history, set_block_list_enabled])
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app, event| {
+            if let RunEvent::Exit = event {
+                if let Some(state) = app.try_state::<Arc<AppState>>() {
+                    tauri::async_runtime::block_on(state.shutdown());
+                }
+            }
+        });
 }
@@ -5,6 +5,11 @@
 use windows::Win32::UI::HiDpi::{SetProcessDpiAwarenessContext, DPI_AWARENESS_CONTEXT_SYSTEM_AWARE};
 
 fn main() {
+    // Run only the HTTP service and spider, without the window
+    if std::env::args().any(|arg| arg == "--headless") {
+        return fuckbilibili_lib::run_headless();
+    }
+
     #[cfg(windows)]
     unsafe {
         // 使用System Aware模式
@@ -0,0 +1,161 @@
+use rusqlite::{Connection, OpenFlags};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::db;
+
+/// Must match `identifier` in tauri.conf.json so the GUI and headless mode share files.
+const IDENTIFIER: &str = "com.twelve.fuckbilibili";
+
+/// Overrides the data directory (database, config and logs) when set.
+const DATA_DIR_ENV: &str = "FUCKBILIBILI_DATA_DIR";
+
+const DB_FILE: &str = "blocked_users.db";
+const CONFIG_FILE: &str = "config.json";
+const LEGACY_LOG_DIR: &str = "log";
+
+#[derive(Debug, Clone)]
+pub struct AppPaths {
+    pub data_dir: PathBuf,
+    pub log_dir: PathBuf,
+}
+
+impl AppPaths {
+    /// Uses the given platform directories unless `FUCKBILIBILI_DATA_DIR` is set.
+    pub fn resolve(default_data_dir: PathBuf, default_log_dir: PathBuf) -> Self {
+        match env::var_os(DATA_DIR_ENV) {
+            Some(dir) if !dir.is_empty() => {
+                let data_dir = PathBuf::from(dir);
+                Self {
+                    log_dir: data_dir.join(LEGACY_LOG_DIR),
+                    data_dir,
+                }
+            }
+            _ => Self {
+                data_dir: default_data_dir,
+                log_dir: default_log_dir,
+            },
+        }
+    }
+
+    /// Same locations Tauri resolves for `app_data_dir` / `app_log_dir`,
+    /// for use when no Tauri app is running (headless mode, CLI).
+    pub fn standard() -> Self {
+        let data_dir = dirs::data_dir()
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join(IDENTIFIER);
+
+        #[cfg(target_os = "macos")]
+        let log_dir = dirs::home_dir()
+            .map(|dir| dir.join("Library/Logs").join(IDENTIFIER))
+            .unwrap_or_else(|| data_dir.join("logs"));
+
+        #[cfg(not(target_os = "macos"))]
+        let log_dir = dirs::data_local_dir()
+            .map(|dir| dir.join(IDENTIFIER).join("logs"))
+            .unwrap_or_else(|| data_dir.join("logs"));
+
+        Self::resolve(data_dir, log_dir)
+    }
+
+    pub fn db_file(&self) -> PathBuf {
+        self.data_dir.join(DB_FILE)
+    }
+
+    pub fn config_file(&self) -> PathBuf {
+        self.data_dir.join(CONFIG_FILE)
+    }
+
+    pub fn create_dirs(&self) -> std::io::Result<()> {
+        fs::create_dir_all(&self.data_dir)?;
+        fs::create_dir_all(&self.log_dir)
+    }
+
+    /// Moves the database, config and logs that older versions kept in the
+    /// working directory. Files already present in the new location are never
+    /// overwritten, so this only does anything the first time. The one
+    /// exception is a database holding nothing at all, e.g. one the CLI
+    /// created before anything was migrated, which the old one replaces.
+    pub fn migrate_legacy(&self) {
+        if let Ok(cwd) = env::current_dir() {
+            self.migrate_legacy_from(&cwd);
+        }
+    }
+
+    /// Like `migrate_legacy`, from `dir` instead of the working directory.
+    pub fn migrate_legacy_from(&self, dir: &Path) {
+        if same_dir(dir, &self.data_dir) {
+            return;
+        }
+
+        let legacy_db = dir.join(DB_FILE);
+        let db_file = self.db_file();
+        if legacy_db.is_file() && db_file.is_file() && is_blank(&db_file) {
+            for suffix in ["", "-wal", "-shm"] {
+                let mut path = db_file.clone().into_os_string();
+                path.push(suffix);
+                let _ = fs::remove_file(path);
+            }
+        }
+        move_if_absent(&legacy_db, &db_file);
+        move_if_absent(&dir.join(CONFIG_FILE), &self.config_file());
+
+        let legacy_logs = dir.join(LEGACY_LOG_DIR);
+        if same_dir(&legacy_logs, &self.log_dir) {
+            return;
+        }
+        if let Ok(entries) = fs::read_dir(&legacy_logs) {
+            for entry in entries.flatten() {
+                move_if_absent(&entry.path(), &self.log_dir.join(entry.file_name()));
+            }
+            // Only succeeds if everything was moved
+            let _ = fs::remove_dir(&legacy_logs);
+        }
+    }
+}
+
+fn same_dir(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Whether the database at `path` holds nothing but what creating it put
+/// there: no rows in any table besides the enabled default block list.
+/// False when it cannot tell, so a database is never replaced by mistake.
+fn is_blank(path: &Path) -> bool {
+    let Ok(conn) = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY) else {
+        return false;
+    };
+    let tables = conn
+        .prepare("SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%'")
+        .and_then(|mut stmt| stmt.query_map([], |row| row.get::<_, String>(0))?.collect::<Result<Vec<_>, _>>());
+    let Ok(tables) = tables else {
+        return false;
+    };
+    tables.iter().all(|table| {
+        let sql = if table == "block_lists" {
+            format!("SELECT EXISTS(SELECT 1 FROM block_lists WHERE NOT (id = {} AND enabled))", db::DEFAULT_LIST)
+        } else {
+            format!("SELECT EXISTS(SELECT 1 FROM \"{}\")", table.replace('"', "\"\""))
+        };
+        matches!(conn.query_row(&sql, [], |row| row.get::<_, bool>(0)), Ok(false))
+    })
+}
+
+fn move_if_absent(from: &Path, to: &Path) {
+    if !from.is_file() || to.exists() {
+        return;
+    }
+    // rename fails across filesystems, fall back to copy + remove
+    if fs::rename(from, to).is_err() {
+        match fs::copy(from, to) {
+            Ok(_) => {
+                let _ = fs::remove_file(from);
+            }
+            Err(e) => eprintln!("Failed to migrate {}: {}", from.display(), e),
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use rusqlite::{Connection, OpenFlags, Result};
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::sync::Mutex as StdMutex;
+use std::time::Duration;
+use tokio::sync::{Mutex, MutexGuard, Semaphore, SemaphorePermit};
+
+use crate::db;
+
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// SQLite access split into one writer and a pool of read-only connections.
+///
+/// The database runs in WAL mode, so readers never wait on the writer or on
+/// each other; only writes are serialized.
+pub struct DbPool {
+    writer: Mutex<Connection>,
+    readers: StdMutex<Vec<Connection>>,
+    reader_slots: Semaphore,
+    reader_count: usize,
+}
+
+impl DbPool {
+    pub fn open<P: AsRef<Path>>(path: P, reader_count: usize) -> Result<Self> {
+        let path = path.as_ref();
+        let writer = db::init_db(path)?;
+        writer.pragma_update(None, "journal_mode", "WAL")?;
+        // Safe with WAL, a crash can only lose the last transactions
+        writer.pragma_update(None, "synchronous", "NORMAL")?;
+        writer.busy_timeout(BUSY_TIMEOUT)?;
+
+        let reader_count = reader_count.max(1);
+        let mut readers = Vec::with_capacity(reader_count);
+        for _ in 0..reader_count {
+            let conn = Connection::open_with_flags(
+                path,
+                OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            )?;
+            conn.busy_timeout(BUSY_TIMEOUT)?;
+            readers.push(conn);
+        }
+
+        Ok(Self {
+            writer: Mutex::new(writer),
+            readers: StdMutex::new(readers),
+            reader_slots: Semaphore::new(reader_count),
+            reader_count,
+        })
+    }
+
+    /// Exclusive access to the single write connection.
+    pub async fn write(&self) -> MutexGuard<'_, Connection> {
+        self.writer.lock().await
+    }
+
+    /// The write connection if nobody holds it, for use outside async code.
+    pub fn try_write(&self) -> Option<MutexGuard<'_, Connection>> {
+        self.writer.try_lock().ok()
+    }
+
+    /// A read-only connection, returned to the pool when dropped.
+    pub async fn read(&self) -> ReadConn<'_> {
+        let permit = self.reader_slots.acquire().await.expect("reader pool closed");
+        let conn = self.readers.lock().unwrap().pop().expect("reader available with permit");
+        ReadConn {
+            pool: self,
+            conn: Some(conn),
+            _permit: permit,
+        }
+    }
+
+    /// Closes every connection. Waits for outstanding readers and writes.
+    pub async fn close(&self) {
+        let mut writer = self.writer.lock().await;
+        // Wait until every reader is back, and keep them from being handed out again
+        if let Ok(permits) = self.reader_slots.acquire_many(self.reader_count as u32).await {
+            permits.forget();
+        }
+        self.reader_slots.close();
+
+        for conn in self.readers.lock().unwrap().drain(..) {
+            if let Err((_, e)) = conn.close() {
+                eprintln!("Failed to close read connection: {}", e);
+            }
+        }
+
+        // Swap in a throwaway connection so the real one can be closed
+        if let Ok(placeholder) = Connection::open_in_memory() {
+            let conn = std::mem::replace(&mut *writer, placeholder);
+            if let Err((_, e)) = conn.close() {
+                eprintln!("Failed to close database: {}", e);
+            }
+        }
+    }
+}
+
+pub struct ReadConn<'a> {
+    pool: &'a DbPool,
+    conn: Option<Connection>,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl Deref for ReadConn<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for ReadConn<'_> {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.conn.as_mut().unwrap()
+    }
+}
+
+impl Drop for ReadConn<'_> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.readers.lock().unwrap().push(conn);
+        }
+    }
+}
@@ -0,0 +1,296 @@
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+use crate::config::OverflowPolicy;
+
+/// How urgently a BV is wanted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    /// Prefetch, e.g. cards further down the feed
+    #[default]
+    Normal,
+    /// Cards the user is looking at right now
+    High,
+}
+
+/// Result of `SpiderQueue::offer`
+#[derive(Debug, PartialEq, Eq)]
+pub enum Offer {
+    Queued,
+    /// Queued in place of this BV, which is no longer queued
+    Evicted(String),
+    /// Full and the policy (or a queue of higher priority BVs) dropped it
+    Dropped,
+    /// Full and the policy is `Reject`
+    Rejected,
+    Closed,
+}
+
+/// A BV taken off the queue
+#[derive(Debug, Clone)]
+pub struct QueuedBv {
+    pub bvid: String,
+    /// When it was queued or last requested again
+    pub queued_at: Instant,
+}
+
+impl QueuedBv {
+    /// Whether it waited longer than `ttl`. A zero `ttl` never expires.
+    pub fn is_expired(&self, ttl: Duration) -> bool {
+        !ttl.is_zero() && self.queued_at.elapsed() > ttl
+    }
+}
+
+/// Bounded queue of BVs for the spider.
+///
+/// High priority BVs are always handed out before normal ones, and within a
+/// priority the most recently queued BV goes first, so the page the user just
+/// opened is resolved before an older backlog.
+///
+/// Clients may tag BVs with a session id; cancelling the session takes the
+/// BVs nobody else asked for off the queue.
+pub struct SpiderQueue {
+    inner: Mutex<Lanes>,
+    capacity: usize,
+    overflow: Mutex<OverflowPolicy>,
+    /// Signalled when a BV is queued or the queue is closed
+    items: Notify,
+    /// Signalled when a BV is taken off or the queue is closed
+    space: Notify,
+}
+
+struct Entry {
+    bvid: String,
+    queued_at: Instant,
+    /// Sessions that asked for this BV
+    sessions: Vec<String>,
+    /// Also asked for without a session, so cancelling never drops it
+    anonymous: bool,
+}
+
+impl Entry {
+    fn new(bvid: &str, session: Option<&str>) -> Self {
+        let mut entry = Self {
+            bvid: bvid.to_string(),
+            queued_at: Instant::now(),
+            sessions: Vec::new(),
+            anonymous: false,
+        };
+        entry.request(session);
+        entry
+    }
+
+    fn request(&mut self, session: Option<&str>) {
+        self.queued_at = Instant::now();
+        match session {
+            Some(session) => {
+                if !self.sessions.iter().any(|s| s == session) {
+                    self.sessions.push(session.to_string());
+                }
+            }
+            None => self.anonymous = true,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Lanes {
+    normal: VecDeque<Entry>,
+    high: VecDeque<Entry>,
+    closed: bool,
+}
+
+impl Lanes {
+    fn len(&self) -> usize {
+        self.normal.len() + self.high.len()
+    }
+
+    fn lane(&mut self, priority: Priority) -> &mut VecDeque<Entry> {
+        match priority {
+            Priority::Normal => &mut self.normal,
+            Priority::High => &mut self.high,
+        }
+    }
+
+    /// Moves an already queued BV to the top of the higher of its current
+    /// and the requested priority. False if it is not queued.
+    fn bump(&mut self, bvid: &str, priority: Priority, session: Option<&str>) -> bool {
+        for current in [Priority::High, Priority::Normal] {
+            let lane = self.lane(current);
+            if let Some(pos) = lane.iter().position(|e| e.bvid == bvid) {
+                let mut entry = lane.remove(pos).unwrap();
+                entry.request(session);
+                self.lane(current.max(priority)).push_back(entry);
+                return true;
+            }
+        }
+        false
+    }
+}
+
+impl SpiderQueue {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Mutex::new(Lanes::default()),
+            capacity: capacity.max(1),
+            overflow: Mutex::new(OverflowPolicy::default()),
+            items: Notify::new(),
+            space: Notify::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Queues `bvid`, waiting for space if the queue is full. False once the
+    /// queue is closed. Callers keep BVs unique, see `AppState::pending_bvs`.
+    pub async fn push(&self, bvid: String, priority: Priority) -> bool {
+        loop {
+            let space = self.space.notified();
+            tokio::pin!(space);
+            space.as_mut().enable();
+
+            match self.try_push_inner(&bvid, priority) {
+                Some(pushed) => return pushed,
+                None => space.await,
+            }
+        }
+    }
+
+    /// Like `push`, but gives up instead of waiting when the queue is full.
+    pub fn try_push(&self, bvid: String, priority: Priority) -> bool {
+        self.try_push_inner(&bvid, priority).unwrap_or(false)
+    }
+
+    /// `None` when full
+    fn try_push_inner(&self, bvid: &str, priority: Priority) -> Option<bool> {
+        let mut lanes = self.inner.lock().unwrap();
+        if lanes.closed {
+            return Some(false);
+        }
+        if lanes.len() >= self.capacity {
+            return None;
+        }
+        lanes.lane(priority).push_back(Entry::new(bvid, None));
+        drop(lanes);
+        self.items.notify_one();
+        Some(true)
+    }
+
+    pub fn set_overflow_policy(&self, policy: OverflowPolicy) {
+        *self.overflow.lock().unwrap() = policy;
+    }
+
+    /// Queues `bvid` on behalf of `session` without waiting; when the queue
+    /// is full the overflow policy decides what gives way.
+    pub fn offer(&self, bvid: &str, priority: Priority, session: Option<&str>) -> Offer {
+        let policy = *self.overflow.lock().unwrap();
+        let mut lanes = self.inner.lock().unwrap();
+        if lanes.closed {
+            return Offer::Closed;
+        }
+
+        let offer = if lanes.len() < self.capacity {
+            Offer::Queued
+        } else {
+            match policy {
+                OverflowPolicy::Reject => return Offer::Rejected,
+                OverflowPolicy::DropNewest => return Offer::Dropped,
+                // Oldest BV of the lowest priority, never one above `priority`
+                OverflowPolicy::DropOldest => match lanes.normal.pop_front() {
+                    Some(oldest) => Offer::Evicted(oldest.bvid),
+                    None if priority == Priority::High => match lanes.high.pop_front() {
+                        Some(oldest) => Offer::Evicted(oldest.bvid),
+                        None => return Offer::Dropped,
+                    },
+                    None => return Offer::Dropped,
+                },
+            }
+        };
+
+        lanes.lane(priority).push_back(Entry::new(bvid, session));
+        drop(lanes);
+        self.items.notify_one();
+        offer
+    }
+
+    /// Moves a queued BV to the top of the higher of its current and the
+    /// requested priority, e.g. when it shows up on a newer page, and
+    /// restarts its expiry. False if it is not queued (anymore).
+    pub fn bump(&self, bvid: &str, priority: Priority, session: Option<&str>) -> bool {
+        self.inner.lock().unwrap().bump(bvid, priority, session)
+    }
+
+    /// Forgets what `session` asked for. BVs no other session (or untagged
+    /// request) wants are taken off the queue and returned.
+    pub fn cancel_session(&self, session: &str) -> Vec<String> {
+        let mut cancelled = Vec::new();
+        let mut lanes = self.inner.lock().unwrap();
+        for priority in [Priority::High, Priority::Normal] {
+            lanes.lane(priority).retain_mut(|entry| {
+                let Some(pos) = entry.sessions.iter().position(|s| s == session) else {
+                    return true;
+                };
+                entry.sessions.remove(pos);
+                if entry.sessions.is_empty() && !entry.anonymous {
+                    cancelled.push(std::mem::take(&mut entry.bvid));
+                    return false;
+                }
+                true
+            });
+        }
+        drop(lanes);
+
+        if !cancelled.is_empty() {
+            self.space.notify_waiters();
+        }
+        cancelled
+    }
+
+    /// Next BV to resolve, waiting until one is queued. `None` once the
+    /// queue is closed and drained.
+    pub async fn pop(&self) -> Option<QueuedBv> {
+        loop {
+            let items = self.items.notified();
+            tokio::pin!(items);
+            items.as_mut().enable();
+
+            {
+                let mut lanes = self.inner.lock().unwrap();
+                if let Some(entry) = lanes.high.pop_back().or_else(|| lanes.normal.pop_back()) {
+                    drop(lanes);
+                    self.space.notify_one();
+                    return Some(QueuedBv {
+                        bvid: entry.bvid,
+                        queued_at: entry.queued_at,
+                    });
+                }
+                if lanes.closed {
+                    return None;
+                }
+            }
+            items.await;
+        }
+    }
+
+    /// Stops accepting BVs and wakes everyone waiting. Queued BVs can still
+    /// be popped.
+    pub fn close(&self) {
+        self.inner.lock().unwrap().closed = true;
+        self.items.notify_waiters();
+        self.space.notify_waiters();
+    }
+}
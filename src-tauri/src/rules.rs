@@ -0,0 +1,259 @@
+use rusqlite::{Connection, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+
+use crate::db::{self, UserProfile, VideoInfo};
+use crate::expr::{quote, Expr};
+use crate::state::AppState;
+
+/// What rules are checked against: a video and its uploader, as far as
+/// they are cached
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Subject<'a> {
+    pub profile: Option<&'a UserProfile>,
+    pub video: Option<&'a VideoInfo>,
+}
+
+/// Hides videos whose cached metadata or uploader profile matches its
+/// expression, without the uploader being on the blocklist. See `expr` for
+/// the syntax.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Rule {
+    /// 0 for a rule that has not been saved yet
+    #[serde(default)]
+    pub id: i64,
+    pub name: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    pub expression: String,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// A rule with its parsed expression; `None` if it does not parse (anymore),
+/// then the rule is listed but never applied
+struct Compiled {
+    rule: Rule,
+    expr: Option<Expr>,
+}
+
+impl Compiled {
+    fn new(rule: Rule) -> Self {
+        let expr = Expr::parse(&rule.expression).ok();
+        Self { rule, expr }
+    }
+
+    /// Expression of an enabled rule
+    fn active(&self) -> Option<&Expr> {
+        self.expr.as_ref().filter(|_| self.rule.enabled)
+    }
+}
+
+/// Expression for a rule stored as JSON conditions, the format before the
+/// expression language: `{"combinator": "and" | "or", "conditions":
+/// [{"field": .., "op": .., "value": ..}]}`. `None` if it is not one.
+pub(crate) fn expression_from_json(json: &str) -> Option<String> {
+    let definition: serde_json::Value = serde_json::from_str(json).ok()?;
+    let joiner = match definition.get("combinator").and_then(|c| c.as_str()) {
+        None | Some("and") => " && ",
+        Some("or") => " || ",
+        Some(_) => return None,
+    };
+    let mut parts = Vec::new();
+    for condition in definition.get("conditions")?.as_array()? {
+        let field = match condition.get("field")?.as_str()? {
+            "level" => "owner.level",
+            "fans" => "owner.fans",
+            "account_age_days" => "owner.age_days",
+            "video_age_days" => "age_days",
+            "tag" => "tags",
+            field @ ("tid" | "tname" | "duration") => field,
+            _ => return None,
+        };
+        let op = match condition.get("op")?.as_str()? {
+            "contains" => "~",
+            op => op,
+        };
+        let value = match condition.get("value")? {
+            serde_json::Value::Number(n) => n.as_i64()?.to_string(),
+            serde_json::Value::String(text) => quote(text),
+            _ => return None,
+        };
+        parts.push(format!("{} {} {}", field, op, value));
+    }
+    let expression = parts.join(joiner);
+    Expr::parse(&expression).ok().map(|_| expression)
+}
+
+/// In-memory copy of the rules table, replaced whenever a rule is saved or
+/// deleted.
+pub struct RuleSet {
+    rules: RwLock<Vec<Compiled>>,
+}
+
+impl RuleSet {
+    pub fn load(conn: &Connection) -> Result<Self> {
+        let set = Self {
+            rules: RwLock::new(Vec::new()),
+        };
+        set.reload(conn)?;
+        Ok(set)
+    }
+
+    pub fn reload(&self, conn: &Connection) -> Result<()> {
+        *self.rules.write().unwrap() = db::list_rules(conn)?.into_iter().map(Compiled::new).collect();
+        Ok(())
+    }
+
+    pub fn list(&self) -> Vec<Rule> {
+        self.rules.read().unwrap().iter().map(|c| c.rule.clone()).collect()
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.rules.read().unwrap().iter().any(|c| c.active().is_some())
+    }
+
+    /// Whether an enabled rule looks at uploader profiles, which then are
+    /// worth fetching
+    pub fn uses_profiles(&self) -> bool {
+        self.rules.read().unwrap().iter().filter_map(Compiled::active).any(Expr::uses_profiles)
+    }
+
+    /// Whether an enabled rule looks at tags, which take an extra request
+    /// per video
+    pub fn uses_tags(&self) -> bool {
+        self.rules.read().unwrap().iter().filter_map(Compiled::active).any(Expr::uses_tags)
+    }
+
+    /// Id of the first enabled rule matching `subject`.
+    pub fn first_match(&self, subject: &Subject, now: i64) -> Option<i64> {
+        self.rules
+            .read()
+            .unwrap()
+            .iter()
+            .find(|c| c.active().is_some_and(|expr| expr.matches(subject, now)))
+            .map(|c| c.rule.id)
+    }
+}
+
+/// Validates and stores `rule`, returning it with its id.
+pub async fn save_rule(state: &AppState, mut rule: Rule) -> Result<Rule, String> {
+    rule.name = rule.name.trim().to_string();
+    if rule.name.is_empty() {
+        return Err("a rule needs a name".to_string());
+    }
+    rule.expression = rule.expression.trim().to_string();
+    check_length(&rule.expression)?;
+    Expr::parse(&rule.expression)?;
+
+    let conn = state.db.write().await;
+    rule.id = db::save_rule(&conn, &rule).map_err(|e| e.to_string())?;
+    state.rules.reload(&conn).map_err(|e| e.to_string())?;
+    Ok(rule)
+}
+
+/// False if there was no such rule.
+pub async fn delete_rule(state: &AppState, id: i64) -> Result<bool, String> {
+    let conn = state.db.write().await;
+    let deleted = db::delete_rule(&conn, id).map_err(|e| e.to_string())?;
+    state.rules.reload(&conn).map_err(|e| e.to_string())?;
+    Ok(deleted)
+}
+
+/// Longest expression accepted, in characters
+const MAX_EXPRESSION_LEN: usize = 2000;
+
+fn check_length(expression: &str) -> Result<(), String> {
+    if expression.chars().count() > MAX_EXPRESSION_LEN {
+        return Err(format!("the expression is longer than {} characters", MAX_EXPRESSION_LEN));
+    }
+    Ok(())
+}
+
+/// How many BVs and uploaders a dry run lists
+const DRY_RUN_SAMPLES: usize = 20;
+
+/// A rule or a list of uploaders to try out before enabling or importing it,
+/// `{"expression": ".."}` or `{"mids": [..]}`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Candidate {
+    Expression(String),
+    Mids(Vec<i64>),
+}
+
+/// What a candidate would hide among the cached videos, besides what the
+/// blocklist hides already
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct DryRun {
+    /// Cached videos checked
+    pub checked: usize,
+    pub bvs: usize,
+    /// Distinct uploaders of those BVs
+    pub uploaders: usize,
+    /// The most recently cached of them
+    pub sample_bvs: Vec<String>,
+    pub sample_uploaders: Vec<i64>,
+}
+
+/// Checks `candidate` against every cached video without changing anything.
+/// Fails with the syntax error of an invalid expression.
+pub async fn dry_run(state: &AppState, candidate: &Candidate) -> Result<DryRun, String> {
+    let expr = match candidate {
+        Candidate::Expression(expression) => {
+            check_length(expression)?;
+            Some(Expr::parse(expression)?)
+        }
+        Candidate::Mids(_) => None,
+    };
+    let conn = state.db.read().await;
+    let videos = db::list_videos(&conn).map_err(|e| e.to_string())?;
+    let profiles = if expr.as_ref().is_some_and(Expr::uses_profiles) {
+        let mids: Vec<i64> = videos.iter().map(|(_, mid, _)| *mid).collect();
+        db::get_user_profiles(&conn, &mids).map_err(|e| e.to_string())?
+    } else {
+        HashMap::new()
+    };
+    drop(conn);
+
+    let listed: HashSet<i64> = match candidate {
+        Candidate::Mids(mids) => mids.iter().copied().collect(),
+        Candidate::Expression(_) => HashSet::new(),
+    };
+    let now = chrono::Utc::now().timestamp();
+    let mut run = DryRun {
+        checked: videos.len(),
+        ..DryRun::default()
+    };
+    let mut uploaders = HashSet::new();
+    for (bvid, mid, video) in &videos {
+        if state.index.is_blocked(*mid) {
+            continue;
+        }
+        let hidden = match &expr {
+            Some(expr) => {
+                let subject = Subject {
+                    profile: profiles.get(mid),
+                    video: Some(video),
+                };
+                expr.matches(&subject, now)
+            }
+            None => listed.contains(mid),
+        };
+        if !hidden {
+            continue;
+        }
+        run.bvs += 1;
+        if run.sample_bvs.len() < DRY_RUN_SAMPLES {
+            run.sample_bvs.push(bvid.clone());
+        }
+        if uploaders.insert(*mid) && run.sample_uploaders.len() < DRY_RUN_SAMPLES {
+            run.sample_uploaders.push(*mid);
+        }
+    }
+    run.uploaders = uploaders.len();
+    Ok(run)
+}
@@ -1,17 +1,47 @@
 use actix_cors::Cors;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServerHandle, ServiceFactory, ServiceRequest, ServiceResponse};
 use actix_web::{rt, web, App, HttpResponse, HttpServer, Responder};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::sync::atomic::Ordering;
-use std::sync::Arc;
+use std::sync::{mpsc, Arc};
 use std::time::Instant;
 
 use crate::db;
+use crate::fetcher;
+use crate::queue::{Offer, Priority};
+use crate::rules::{self, Candidate, Rule, Subject};
+use crate::spider;
 use crate::state::AppState;
 
 #[derive(Deserialize)]
 struct BlockForm {
     mid: String,
     username: Option<String>,
+    /// Seconds to block for, for a temporary block
+    duration: Option<String>,
+    /// Unix seconds a temporary block ends at, instead of `duration`
+    expires_at: Option<String>,
+    /// Name of the block list, created if new; the default list if missing
+    list: Option<String>,
+}
+
+impl BlockForm {
+    /// End of the block, `None` for good. `Err` for a malformed, past or
+    /// doubly given expiry.
+    fn expiry(&self, now: i64) -> Result<Option<i64>, ()> {
+        let positive = |s: &str| s.parse::<i64>().ok().filter(|v| *v > 0 && s.chars().all(char::is_numeric));
+        let given = |field: &Option<String>| field.clone().filter(|s| !s.is_empty());
+        match (given(&self.duration), given(&self.expires_at)) {
+            (None, None) => Ok(None),
+            (Some(duration), None) => positive(&duration).and_then(|d| now.checked_add(d)).map(Some).ok_or(()),
+            (None, Some(expires_at)) => positive(&expires_at).filter(|t| *t > now).map(Some).ok_or(()),
+            (Some(_), Some(_)) => Err(()),
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -22,6 +52,29 @@ struct RemoveForm {
 #[derive(Deserialize)]
 struct IsBlockedBvsForm {
     bvs: String, // comma separated
+    /// "high" for cards currently on screen
+    #[serde(default)]
+    priority: Priority,
+    /// Client-chosen id (e.g. per tab) that `/cancel` can withdraw
+    session: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CancelForm {
+    session: String,
+}
+
+#[derive(Serialize)]
+struct UserProfileResponse {
+    msg: String,
+    #[serde(flatten)]
+    lookup: spider::ProfileLookup,
+}
+
+#[derive(Serialize)]
+struct CancelResponse {
+    msg: String,
+    cancelled: usize,
 }
 
 #[derive(Serialize)]
@@ -29,6 +82,29 @@ struct IsBlockedBvsResponse {
     msg: String,
     mid: Vec<Option<i64>>,
     result: Vec<String>,
+    /// Id of the rule that hid the video, when it was a rule rather than
+    /// the blocklist
+    rule: Vec<Option<i64>>,
+}
+
+#[derive(Deserialize)]
+struct DeleteRuleForm {
+    id: i64,
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    limit: Option<usize>,
+}
+
+/// Entries `/history` returns unless asked for fewer
+const HISTORY_LIMIT: usize = 200;
+
+#[derive(Deserialize)]
+struct BlockListForm {
+    name: String,
+    /// "true" or "false"
+    enabled: String,
 }
 
 async fn add_user(form: web::Form<BlockForm>, state: web::Data<Arc<AppState>>) -> impl Responder {
@@ -41,17 +117,19 @@ async fn add_user(form: web::Form<BlockForm>, state: web::Data<Arc<AppState>>) -
         Ok(v) => v,
         Err(_) => return HttpResponse::Ok().body("ERR1"),
     };
+    let Ok(expires_at) = form.expiry(chrono::Utc::now().timestamp()) else {
+        return HttpResponse::Ok().body("ERR1");
+    };
 
-    let conn = state.db_conn.lock().await;
-    match db::add_user(&conn, mid, form.username.as_deref()) {
-        Ok(true) => {
-            state
-                .db_stats
-                .blocked_user_count
-                .fetch_add(1, Ordering::Relaxed);
+    let list = form.list.as_deref().map(str::trim).filter(|name| !name.is_empty());
+    let conn = state.db.write().await;
+    match db::block_in_list(&conn, mid, form.username.as_deref(), list, expires_at) {
+        Ok(Some((list_id, expires_at))) => {
+            state.index.add_block(mid, list_id, expires_at);
+            state.recount_blocked_users(&conn);
             HttpResponse::Ok().body("OK")
         }
-        Ok(false) => HttpResponse::Ok().body("ERR2"),
+        Ok(None) => HttpResponse::Ok().body("ERR2"),
         Err(_) => HttpResponse::Ok().body("ERR2"),
     }
 }
@@ -70,13 +148,11 @@ async fn remove_user(
         Err(_) => return HttpResponse::Ok().body("ERR1"),
     };
 
-    let conn = state.db_conn.lock().await;
+    let conn = state.db.write().await;
     match db::remove_user(&conn, mid) {
         Ok(true) => {
-            state
-                .db_stats
-                .blocked_user_count
-                .fetch_sub(1, Ordering::Relaxed);
+            state.index.remove_blocked(mid);
+            state.recount_blocked_users(&conn);
             HttpResponse::Ok().body("OK")
         }
         Ok(false) => HttpResponse::Ok().body("ERR2"),
@@ -99,11 +175,10 @@ async fn is_user_exist(
         Err(_) => return HttpResponse::Ok().body("ERR1"),
     };
 
-    let conn = state.db_conn.lock().await;
-    let res = match db::is_user_exist(&conn, mid) {
-        Ok(true) => HttpResponse::Ok().body("True"),
-        Ok(false) => HttpResponse::Ok().body("False"),
-        Err(_) => HttpResponse::Ok().body("ERR2"),
+    let res = if state.index.is_blocked(mid) {
+        HttpResponse::Ok().body("True")
+    } else {
+        HttpResponse::Ok().body("False")
     };
 
     state
@@ -131,7 +206,6 @@ async fn is_user_exist_s_impl(
     let mids: Vec<&str> = mids_str.split(',').collect();
 
     let mut results = Vec::new();
-    let conn = state.db_conn.lock().await;
 
     for mid_str in mids {
         if !mid_str.chars().all(char::is_numeric) {
@@ -139,11 +213,8 @@ async fn is_user_exist_s_impl(
             continue;
         }
         match mid_str.parse::<i64>() {
-            Ok(mid) => match db::is_user_exist(&conn, mid) {
-                Ok(true) => results.push("True".to_string()),
-                Ok(false) => results.push("False".to_string()),
-                Err(_) => results.push("ERR2".to_string()),
-            },
+            Ok(mid) if state.index.is_blocked(mid) => results.push("True".to_string()),
+            Ok(_) => results.push("False".to_string()),
             Err(_) => results.push("ERR1".to_string()),
         }
     }
@@ -166,42 +237,99 @@ async fn is_blocked_bvs(
     let start = Instant::now();
     let bvs: Vec<&str> = form.bvs.split(',').collect();
 
-    let mut mids = Vec::new();
-    let mut results = Vec::new();
-
-    let conn = state.db_conn.lock().await;
+    let mut missing = Vec::new();
 
     state.spider_stats.total_received_count.fetch_add(bvs.len(), Ordering::Relaxed);
-    for bv in bvs {
-        match db::get_mid_by_bv(&conn, bv) {
+
+    // Answer from the in-memory index first, then resolve the rest in one
+    // query. Anything but a BV id would end up in a request URL, so it is
+    // answered ERR1 and never queued.
+    let mut lookups: Vec<Result<Option<i64>, &str>> = bvs
+        .iter()
+        .map(|bv| if fetcher::is_bvid(bv) { Ok(state.index.get_mid_by_bv(bv)) } else { Err("ERR1") })
+        .collect();
+    let uncached: Vec<&str> = bvs
+        .iter()
+        .zip(&lookups)
+        .filter(|(_, mid)| matches!(mid, Ok(None)))
+        .map(|(bv, _)| *bv)
+        .collect();
+
+    if !uncached.is_empty() {
+        let conn = state.db.read().await;
+        let resolved = db::get_mids_by_bvs(&conn, &uncached);
+        drop(conn);
+
+        let mut resolved = match resolved {
+            Ok(mids) => mids.into_iter().map(Ok).collect::<Vec<_>>(),
+            Err(_) => vec![Err("ERR2"); uncached.len()],
+        }
+        .into_iter();
+        for (bv, lookup) in bvs.iter().zip(lookups.iter_mut()) {
+            if matches!(lookup, Ok(None)) {
+                *lookup = resolved.next().unwrap();
+                if let Ok(Some(mid)) = lookup {
+                    state.index.cache_bv_mid(bv, *mid);
+                }
+            }
+        }
+    }
+
+    let mut mids = Vec::with_capacity(bvs.len());
+    let mut results = Vec::with_capacity(bvs.len());
+    for (bv, lookup) in bvs.iter().zip(lookups) {
+        match lookup {
             Ok(Some(mid)) => {
                 mids.push(Some(mid));
-                match db::is_user_exist(&conn, mid) {
-                    Ok(true) => results.push("True".to_string()),
-                    Ok(false) => results.push("False".to_string()),
-                    Err(_) => results.push("ERR2".to_string()),
+                if state.index.is_blocked(mid) {
+                    results.push("True".to_string());
+                } else {
+                    results.push("False".to_string());
                 }
             }
             Ok(None) => {
                 mids.push(None);
+                missing.push((results.len(), *bv));
                 results.push("None".to_string());
-
-                // Deduplication logic
-                let mut pending = state.pending_bvs.lock().await;
-                if !pending.contains(bv) {
-                    pending.insert(bv.to_string());
-                    // Only queue if not already pending
-                    let _ = state.spider_queue.send(bv.to_string()).await;
-                    state
-                        .spider_stats
-                        .queue_size
-                        .fetch_add(1, Ordering::Relaxed);
-                }
             }
-            Err(_) => {
+            Err(code) => {
                 mids.push(None);
-                results.push("ERR2".to_string());
+                results.push(code.to_string());
+            }
+        }
+    }
+
+    let rules = apply_rules(&state, &bvs, &mids, &mut results).await;
+
+    // Queue uncached BVs without holding a DB connection, never waiting on
+    // a full queue. Reversed so the first card of the page is popped first.
+    let stats = &state.spider_stats;
+    for (i, bv) in missing.into_iter().rev() {
+        // Deduplication logic
+        let mut pending = state.pending_bvs.lock().await;
+        if pending.contains(bv) {
+            // Seen again on a newer page: move it up if it is still waiting
+            state.spider_queue.bump(bv, form.priority, form.session.as_deref());
+            continue;
+        }
+        match state.spider_queue.offer(bv, form.priority, form.session.as_deref()) {
+            Offer::Queued => {
+                pending.insert(bv.to_string());
+                stats.queue_size.fetch_add(1, Ordering::Relaxed);
+            }
+            Offer::Evicted(oldest) => {
+                pending.remove(&oldest);
+                pending.insert(bv.to_string());
+                stats.overflow_dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            Offer::Dropped => {
+                stats.overflow_dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            Offer::Rejected => {
+                stats.overflow_rejected.fetch_add(1, Ordering::Relaxed);
+                results[i] = "Busy".to_string();
             }
+            Offer::Closed => {}
         }
     }
 
@@ -218,6 +346,195 @@ async fn is_blocked_bvs(
         msg: "OK".to_string(),
         mid: mids,
         result: results,
+        rule: rules,
+    })
+}
+
+/// Marks videos whose cached metadata or uploader profile matches an
+/// enabled rule as blocked. Returns the matched rule per video. Profiles
+/// and tags not cached yet are requested, so those rules apply from a later
+/// check on.
+async fn apply_rules(
+    state: &AppState,
+    bvs: &[&str],
+    mids: &[Option<i64>],
+    results: &mut [String],
+) -> Vec<Option<i64>> {
+    let mut matched = vec![None; mids.len()];
+    if !state.rules.is_active() {
+        return matched;
+    }
+
+    // Resolved videos not already blocked by their uploader
+    let candidates: Vec<(usize, i64)> = mids
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| results[*i] == "False")
+        .filter_map(|(i, mid)| mid.map(|mid| (i, mid)))
+        .collect();
+    if candidates.is_empty() {
+        return matched;
+    }
+
+    let with_profiles = state.rules.uses_profiles();
+    let (videos, profiles) = {
+        let conn = state.db.read().await;
+        let bvids: Vec<&str> = candidates.iter().map(|(i, _)| bvs[*i]).collect();
+        let videos = db::get_videos(&conn, &bvids);
+        let profiles = if with_profiles {
+            let mids: Vec<i64> = candidates.iter().map(|(_, mid)| *mid).collect();
+            db::get_user_profiles(&conn, &mids)
+        } else {
+            Ok(HashMap::new())
+        };
+        match (videos, profiles) {
+            (Ok(videos), Ok(profiles)) => (videos, profiles),
+            _ => return matched,
+        }
+    };
+
+    let now = chrono::Utc::now().timestamp();
+    let mut unknown = Vec::new();
+    for (i, mid) in candidates {
+        let profile = profiles.get(&mid);
+        if with_profiles && profile.is_none_or(spider::is_stale) {
+            unknown.push(mid);
+        }
+        let subject = Subject {
+            profile,
+            video: videos.get(bvs[i]),
+        };
+        if let Some(rule) = state.rules.first_match(&subject, now) {
+            results[i] = "True".to_string();
+            matched[i] = Some(rule);
+        }
+    }
+    if !unknown.is_empty() {
+        spider::request_profiles(state, unknown);
+    }
+    if state.rules.uses_tags() {
+        let untagged = videos.iter().filter(|(_, video)| video.tags.is_none()).map(|(bvid, _)| bvid.as_str());
+        spider::request_tags(state, untagged);
+    }
+    matched
+}
+
+async fn list_rules(state: web::Data<Arc<AppState>>) -> impl Responder {
+    HttpResponse::Ok().json(state.rules.list())
+}
+
+/// Creates a rule, or updates the one with the given id. Takes the rule as
+/// JSON, e.g. `{"name": "new accounts", "expression": "owner.level < 2"}`;
+/// answers 400 with the syntax error for an invalid expression.
+async fn save_rule(rule: web::Json<Rule>, state: web::Data<Arc<AppState>>) -> impl Responder {
+    match rules::save_rule(&state, rule.into_inner()).await {
+        Ok(rule) => HttpResponse::Ok().json(rule),
+        Err(e) => HttpResponse::BadRequest().body(e),
+    }
+}
+
+async fn delete_rule(form: web::Form<DeleteRuleForm>, state: web::Data<Arc<AppState>>) -> impl Responder {
+    match rules::delete_rule(&state, form.id).await {
+        Ok(true) => HttpResponse::Ok().body("OK"),
+        Ok(false) => HttpResponse::Ok().body("ERR2"),
+        Err(_) => HttpResponse::Ok().body("ERR2"),
+    }
+}
+
+/// Reloads the blocklist from the database after another process, like the
+/// blocklist CLI, wrote to it.
+async fn reload(state: web::Data<Arc<AppState>>) -> impl Responder {
+    let conn = state.db.read().await;
+    match state.index.reload(&conn) {
+        Ok(()) => {
+            state.recount_blocked_users(&conn);
+            HttpResponse::Ok().body("OK")
+        }
+        Err(_) => HttpResponse::Ok().body("ERR2"),
+    }
+}
+
+/// Block lists with how many users each holds
+async fn list_block_lists(state: web::Data<Arc<AppState>>) -> impl Responder {
+    let conn = state.db.read().await;
+    match db::list_block_lists(&conn) {
+        Ok(lists) => HttpResponse::Ok().json(lists),
+        Err(_) => HttpResponse::Ok().body("ERR2"),
+    }
+}
+
+/// Switches a block list on or off. Its users stay in the database but are
+/// not reported as blocked while it is off.
+async fn set_block_list(form: web::Form<BlockListForm>, state: web::Data<Arc<AppState>>) -> impl Responder {
+    let enabled = match form.enabled.as_str() {
+        "true" => true,
+        "false" => false,
+        _ => return HttpResponse::Ok().body("ERR1"),
+    };
+    match set_block_list_enabled(&state, form.name.trim(), enabled).await {
+        Ok(true) => HttpResponse::Ok().body("OK"),
+        Ok(false) | Err(_) => HttpResponse::Ok().body("ERR2"),
+    }
+}
+
+/// False if there is no list called `name`.
+pub async fn set_block_list_enabled(state: &AppState, name: &str, enabled: bool) -> rusqlite::Result<bool> {
+    let conn = state.db.write().await;
+    let Some(id) = db::set_block_list_enabled(&conn, name, enabled)? else {
+        return Ok(false);
+    };
+    state.index.set_list_enabled(id, enabled);
+    state.recount_blocked_users(&conn);
+    Ok(true)
+}
+
+/// Temporary blocks that ran out, most recently ended first. `limit` is
+/// capped at `HISTORY_LIMIT`.
+async fn block_history(query: web::Query<HistoryQuery>, state: web::Data<Arc<AppState>>) -> impl Responder {
+    let limit = query.limit.unwrap_or(HISTORY_LIMIT).min(HISTORY_LIMIT);
+    let conn = state.db.read().await;
+    match db::list_block_history(&conn, limit) {
+        Ok(history) => HttpResponse::Ok().json(history),
+        Err(_) => HttpResponse::Ok().body("ERR2"),
+    }
+}
+
+/// What a rule or a list of uploaders would hide among the cached videos,
+/// without saving anything. Takes `{"expression": ".."}` or `{"mids": [..]}`;
+/// answers 400 with the syntax error for an invalid expression.
+async fn dry_run(candidate: web::Json<Candidate>, state: web::Data<Arc<AppState>>) -> impl Responder {
+    match rules::dry_run(&state, &candidate).await {
+        Ok(run) => HttpResponse::Ok().json(run),
+        Err(e) => HttpResponse::BadRequest().body(e),
+    }
+}
+
+/// Cached name, avatar, followers and level of an uploader. A missing or
+/// stale profile comes back with `pending` set and is fetched in the
+/// background.
+async fn user_profile(query: web::Query<RemoveForm>, state: web::Data<Arc<AppState>>) -> impl Responder {
+    let mid = match query.mid.parse::<i64>() {
+        Ok(v) if query.mid.chars().all(char::is_numeric) => v,
+        _ => return HttpResponse::Ok().body("ERR1"),
+    };
+
+    match spider::lookup_profile(&state, mid).await {
+        Ok(lookup) => HttpResponse::Ok().json(UserProfileResponse {
+            msg: "OK".to_string(),
+            lookup,
+        }),
+        Err(_) => HttpResponse::Ok().body("ERR2"),
+    }
+}
+
+/// Drops the queued BVs only `session` was waiting for, e.g. when its tab
+/// was closed.
+async fn cancel(form: web::Form<CancelForm>, state: web::Data<Arc<AppState>>) -> impl Responder {
+    let cancelled = state.spider_queue.cancel_session(&form.session);
+    spider::skip_bvs(&state, &cancelled).await;
+    HttpResponse::Ok().json(CancelResponse {
+        msg: "OK".to_string(),
+        cancelled: cancelled.len(),
     })
 }
 
@@ -225,46 +542,165 @@ async fn is_alive() -> impl Responder {
     HttpResponse::Ok().body("OK")
 }
 
-pub fn run_server(state: Arc<AppState>) {
-    std::thread::spawn(move || {
-        let sys = rt::System::new();
-
-        sys.block_on(async move {
-            let data = web::Data::new(state.clone());
-
-            let server_factory = HttpServer::new(move || {
-                App::new()
-                    .wrap(Cors::permissive())
-                    .app_data(data.clone())
-                    .route("/block", web::post().to(add_user))
-                    .route("/remove", web::post().to(remove_user))
-                    .route("/isExist", web::get().to(is_user_exist))
-                    .route("/isExistS", web::post().to(is_user_exist_s_impl))
-                    .route("/isBlockedBVS", web::post().to(is_blocked_bvs))
-                    .route("/ok", web::get().to(is_alive))
-            });
-
-            match server_factory.bind(("127.0.0.1", 22332)) {
-                Ok(server) => {
-                    // Port bound successfully
+/// Registers the userscript API routes. Expects `web::Data<Arc<AppState>>`
+/// to be provided as app data.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/block", web::post().to(add_user))
+        .route("/remove", web::post().to(remove_user))
+        .route("/isExist", web::get().to(is_user_exist))
+        .route("/isExistS", web::post().to(is_user_exist_s_impl))
+        .route("/isBlockedBVS", web::post().to(is_blocked_bvs))
+        .route("/cancel", web::post().to(cancel))
+        .route("/userProfile", web::get().to(user_profile))
+        .route("/rules", web::get().to(list_rules))
+        .route("/rules", web::post().to(save_rule))
+        .route("/rules/delete", web::post().to(delete_rule))
+        .route("/dryRun", web::post().to(dry_run))
+        .route("/lists", web::get().to(list_block_lists))
+        .route("/reload", web::post().to(reload))
+        .route("/lists", web::post().to(set_block_list))
+        .route("/history", web::get().to(block_history))
+        .route("/ok", web::get().to(is_alive));
+}
 
-                    state.server_status.store(1, Ordering::Relaxed);
+/// The full userscript API app, ready to be served or embedded.
+pub fn app(
+    state: Arc<AppState>,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    App::new()
+        .wrap(Cors::permissive())
+        .app_data(web::Data::new(state))
+        .configure(configure)
+}
 
-                    if let Err(e) = server.run().await {
-                        eprintln!("Server error: {}", e);
+pub const DEFAULT_ADDR: (&str, u16) = ("127.0.0.1", 22332);
 
-                        // If run fails after bind (rare, but possible)
+/// A running HTTP server on its own thread with a dedicated actix system.
+/// It does not keep the state alive itself, as the state owns it.
+pub struct Server {
+    addr: SocketAddr,
+    handle: ServerHandle,
+}
 
-                        state.server_status.store(2, Ordering::Relaxed);
+impl Server {
+    /// Binds `addr` and starts serving. Blocks until the bind result is
+    /// known; async callers go through [`Server::start_async`].
+    pub fn start<A: ToSocketAddrs>(state: Arc<AppState>, addr: A) -> io::Result<Server> {
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to bind"))?;
+        let (tx, rx) = mpsc::channel();
+        let thread_state = state;
+
+        std::thread::spawn(move || {
+            let sys = rt::System::new();
+
+            sys.block_on(async move {
+                let app_state = thread_state.clone();
+                let server_factory = HttpServer::new(move || app(app_state.clone()))
+                    // Keep shutdown snappy, handlers never take long
+                    .shutdown_timeout(5);
+
+                let server = match server_factory.bind(addr) {
+                    Ok(server) => server,
+                    Err(e) => {
+                        let _ = tx.send(Err(e));
+                        return;
                     }
-                }
+                };
+
+                // Port bound successfully
+                let bound = server.addrs()[0];
+                let server = server.run();
+                let _ = tx.send(Ok((bound, server.handle())));
+
+                if let Err(e) = server.await {
+                    eprintln!("Server error: {}", e);
 
-                Err(e) => {
-                    eprintln!("Can not bind to port 22332: {}", e);
+                    // If run fails after bind (rare, but possible)
 
-                    state.server_status.store(2, Ordering::Relaxed);
+                    thread_state.server_status.store(2, Ordering::Relaxed);
                 }
-            }
+            });
         });
-    });
+
+        let (addr, handle) = rx
+            .recv()
+            .map_err(|_| io::Error::other("server thread exited before binding"))??;
+        Ok(Server { addr, handle })
+    }
+
+    /// [`Server::start`] without blocking the async runtime while the server
+    /// thread binds.
+    pub async fn start_async<A: ToSocketAddrs + Send + 'static>(state: Arc<AppState>, addr: A) -> io::Result<Server> {
+        tokio::task::spawn_blocking(move || Server::start(state, addr))
+            .await
+            .map_err(io::Error::other)?
+    }
+
+    /// The bound address, useful when started on port 0.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Stops accepting connections and waits for in-flight requests.
+    pub async fn stop(self) {
+        self.handle.stop(true).await;
+    }
+
+    /// Stops the server and binds the same address again.
+    pub async fn restart(self, state: Arc<AppState>) -> io::Result<Server> {
+        let addr = self.addr;
+        self.stop().await;
+        Server::start_async(state, addr).await
+    }
+}
+
+/// Starts the server on the default port and records the outcome in the state.
+pub fn run_server(state: Arc<AppState>) {
+    run_server_on(state, DEFAULT_ADDR)
+}
+
+/// Starts the server on `addr` and records the outcome in the state.
+pub fn run_server_on<A: ToSocketAddrs + Copy + std::fmt::Debug>(state: Arc<AppState>, addr: A) {
+    match Server::start(state.clone(), addr) {
+        Ok(server) => {
+            state.server_status.store(1, Ordering::Relaxed);
+            *state.server.lock().unwrap() = Some(server);
+        }
+        Err(e) => {
+            eprintln!("Can not bind to {:?}: {}", addr, e);
+            state.server_status.store(2, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Restarts the running server, or retries the default port if it never started.
+pub async fn restart_server(state: Arc<AppState>) -> io::Result<()> {
+    let current = state.server.lock().unwrap().take();
+    let result = match current {
+        Some(server) => server.restart(state.clone()).await,
+        None => Server::start_async(state.clone(), DEFAULT_ADDR).await,
+    };
+
+    match result {
+        Ok(server) => {
+            state.server_status.store(1, Ordering::Relaxed);
+            *state.server.lock().unwrap() = Some(server);
+            Ok(())
+        }
+        Err(e) => {
+            state.server_status.store(2, Ordering::Relaxed);
+            Err(e)
+        }
+    }
 }
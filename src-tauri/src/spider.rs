@@ -1,49 +1,38 @@
-use crate::db;
-use crate::state::AppState;
-use crate::config::ConfigManager;
-use reqwest::{Client, Proxy};
-use serde::Deserialize;
+use crate::db::{self, UserProfile, VideoInfo};
+use crate::state::{AppState, SpiderStats};
+use crate::config::{ConfigManager, FetchStrategy};
+use crate::fetcher::{FetchError, MetadataFetcher, UserMeta, VideoMeta};
+use serde::Serialize;
+use crate::queue::Priority;
 use std::sync::Arc;
 use std::sync::atomic::Ordering;
-use std::time::Instant;
-use tokio::sync::mpsc;
+use std::time::Duration;
 use tokio::sync::Semaphore;
+use tokio_util::task::TaskTracker;
 use std::fs::{self, OpenOptions};
 use std::io::Write;
-use serde_json;
 use chrono::Local;
 use lazy_static::lazy_static;
+use std::path::PathBuf;
 use std::sync::Mutex;
 
-#[derive(Deserialize, Debug)]
-struct BilibiliApiResponse {
-    code: i32,
-    data: Option<BilibiliApiData>,
-}
-
-#[derive(Deserialize, Debug)]
-struct BilibiliApiData {
-    owner: Option<BilibiliOwner>,
-}
-
-#[derive(Deserialize, Debug)]
-struct BilibiliOwner {
-    mid: i64,
+lazy_static! {
+    // Also serializes writes to the log file
+    static ref LOG_DIR: Mutex<PathBuf> = Mutex::new(PathBuf::from("./log"));
 }
 
-lazy_static! {
-    static ref LOG_LOCK: Mutex<()> = Mutex::new(());
+pub fn set_log_dir(dir: PathBuf) {
+    *LOG_DIR.lock().unwrap() = dir;
 }
 
-fn write_log(message: &str) {
-    let _guard = LOG_LOCK.lock().unwrap();
-    let log_dir = "./log";
-    if let Err(_) = fs::create_dir_all(log_dir) {
+pub(crate) fn write_log(message: &str) {
+    let log_dir = LOG_DIR.lock().unwrap();
+    if let Err(_) = fs::create_dir_all(&*log_dir) {
         return;
     }
 
     let date = Local::now().format("%Y-%m-%d").to_string();
-    let file_path = format!("{}/spider_{}.log", log_dir, date);
+    let file_path = log_dir.join(format!("spider_{}.log", date));
 
     if let Ok(mut file) = OpenOptions::new()
         .create(true)
@@ -58,7 +47,7 @@ fn write_log(message: &str) {
 }
 
 fn clean_old_logs() {
-    let log_dir = "./log";
+    let log_dir = LOG_DIR.lock().unwrap().clone();
     if let Ok(entries) = fs::read_dir(log_dir) {
         let now = Local::now();
         for entry in entries {
@@ -81,50 +70,105 @@ fn clean_old_logs() {
     }
 }
 
-pub async fn start_spider(state: Arc<AppState>, mut rx: mpsc::Receiver<String>, config: Arc<ConfigManager>) {
+pub async fn start_spider(
+    state: Arc<AppState>,
+    config: Arc<ConfigManager>,
+    fetcher: Arc<dyn MetadataFetcher>,
+) {
     // Clean old logs on startup
     clean_old_logs();
 
-    // Initial setup
-    let current_config = config.get_config();
-    let mut current_proxy_url = current_config.proxy_url;
-    let mut current_proxy_enabled = current_config.proxy_enabled;
-    let mut client = build_client(&current_proxy_url, current_proxy_enabled);
-
     // Limit concurrent API requests to avoid IP bans while maintaining high throughput
     let semaphore = Arc::new(Semaphore::new(16));
+    let tracker = TaskTracker::new();
 
-    while let Some(bvid) = rx.recv().await {
-        // Check for proxy config change
+    restore_queue(&state).await;
+
+    // Periodic fetcher upkeep, e.g. re-probing unhealthy proxies
+    {
+        let state = state.clone();
+        let fetcher = fetcher.clone();
+        let config = config.clone();
+        tracker.spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(MAINTENANCE_INTERVAL) => {
+                        fetcher.apply_config(&config.get_config());
+                        fetcher.maintain(&state.spider_stats).await;
+                    }
+                    _ = state.shutdown_token.cancelled() => break,
+                }
+            }
+        });
+    }
+
+    tracker.spawn(profile_worker(state.clone(), fetcher.clone()));
+
+    let queue = &state.spider_queue;
+    let shutdown = &state.shutdown_token;
+
+    'spider: loop {
+        // Take a request slot and wait out pauses before taking BVs, so they
+        // wait in the priority queue rather than in spawned tasks
+        let permit = tokio::select! {
+            permit = semaphore.clone().acquire_owned() => permit.unwrap(),
+            _ = shutdown.cancelled() => break,
+        };
+
+        // Wait if paused
+        while state.spider_stats.is_paused.load(Ordering::Relaxed) {
+            tokio::select! {
+                _ = tokio::time::sleep(std::time::Duration::from_millis(500)) => {}
+                _ = shutdown.cancelled() => break 'spider,
+            }
+        }
+
+        // On shutdown queued BVs stay in pending_bvs and are persisted instead
+        let item = tokio::select! {
+            item = queue.pop() => match item {
+                Some(item) => item,
+                None => break,
+            },
+            _ = shutdown.cancelled() => break,
+        };
+
+        // Let the fetcher pick up proxy changes
         let new_config = config.get_config();
-        let new_proxy_url = new_config.proxy_url;
-        let new_proxy_enabled = new_config.proxy_enabled;
-        
-        if new_proxy_url != current_proxy_url || new_proxy_enabled != current_proxy_enabled {
-            write_log(&format!("Proxy config changed. Rebuilding client..."));
-            client = build_client(&new_proxy_url, new_proxy_enabled);
-            current_proxy_url = new_proxy_url;
-            current_proxy_enabled = new_proxy_enabled;
-        }
-        
-        let state_clone = state.clone();
-        let client_clone = client.clone();
-        let bvid_clone = bvid.clone();
-        let sem_clone = semaphore.clone();
+        fetcher.apply_config(&new_config);
 
-        tokio::spawn(async move {
-            // Wait for a slot to perform the request
-            let _permit = sem_clone.acquire().await.unwrap();
+        // Nobody is waiting for an answer this old anymore
+        let ttl = Duration::from_secs(new_config.queue_ttl_secs);
+        if item.is_expired(ttl) {
+            skip_bvs(&state, &[item.bvid]).await;
+            continue;
+        }
 
-            // Wait if paused
-            while state_clone.spider_stats.is_paused.load(Ordering::Relaxed) {
-                tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        // Group BVs arriving within a short window into one batch request
+        let mut bvids = vec![item.bvid];
+        if new_config.fetch_strategy == FetchStrategy::Batch {
+            let deadline = tokio::time::Instant::now() + BATCH_WINDOW;
+            let mut expired = Vec::new();
+            while bvids.len() < BATCH_SIZE {
+                match tokio::time::timeout_at(deadline, queue.pop()).await {
+                    Ok(Some(item)) if item.is_expired(ttl) => expired.push(item.bvid),
+                    Ok(Some(item)) => bvids.push(item.bvid),
+                    _ => break,
+                }
             }
+            skip_bvs(&state, &expired).await;
+        }
+
+
+        let state_clone = state.clone();
+        let fetcher_clone = fetcher.clone();
+
+        tracker.spawn(async move {
+            let _permit = permit;
 
             //TODO: 似乎是不必要的
             // 1. Double check cache (DB read is fast)
             // {
-            //     let conn = state_clone.db_conn.lock().await;
+            //     let conn = state_clone.db.read().await;
             //     if let Ok(Some(_)) = db::get_mid_by_bv(&conn, &bvid_clone) {
             //         // Already cached, just finish
             //         state_clone.spider_stats.queue_size.fetch_sub(1, Ordering::Relaxed);
@@ -133,93 +177,315 @@ pub async fn start_spider(state: Arc<AppState>, mut rx: mpsc::Receiver<String>,
             // }
 
             // 2. Perform API Request
-            state_clone.spider_stats.actual_api_req_count.fetch_add(1, Ordering::Relaxed);
-            let start_time = Instant::now();
-            let url = format!("https://api.bilibili.com/x/web-interface/view?bvid={}", bvid_clone);
-            
-            let mut success = false;
-            // Retry logic could be added here, but keeping it simple for speed
-            match client_clone.get(&url).send().await {
-                Ok(resp) => {
-                    // 获取响应文本用于日志记录
-                    match resp.text().await {
-                        Ok(text) => {                            
-                            // 解析JSON
-                            match serde_json::from_str::<BilibiliApiResponse>(&text) {
-                                Ok(json) => {
-                                    let duration = start_time.elapsed().as_millis() as u64;
-                                    state_clone.spider_stats.req_time_sum.fetch_add(duration, Ordering::Relaxed);
-
-                                    if json.code == 0 {
-                                        if let Some(data) = json.data {
-                                            if let Some(owner) = data.owner {
-                                                let conn = state_clone.db_conn.lock().await;
-                                                // Update cache
-                                                if let Ok(_) = db::cache_bv_mid(&conn, &bvid_clone, owner.mid) {
-                                                    state_clone.spider_stats.bv_cache_count.fetch_add(1, Ordering::Relaxed);
-                                                    success = true;
-                                                }
-                                            }
-                                        }
-                                    } else {
-                                        // Logic for known API errors (e.g., -404)
-                                        write_log(&format!("API error for {}: code {}", bvid_clone, json.code));
-                                    }
-                                }
-                                Err(e) => {
-                                    write_log(&format!("JSON parse error for {}: {}", bvid_clone, e));
-                                    write_log(&format!("Response for {}: {}", bvid_clone, text));
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            write_log(&format!("Failed to read response text for {}: {}", bvid_clone, e));
+            let stats = &state_clone.spider_stats;
+            let results = if bvids.len() == 1 {
+                vec![fetcher_clone.fetch(&bvids[0], stats).await]
+            } else {
+                fetcher_clone.fetch_many(&bvids, stats).await
+            };
+
+            // Tags take a request per video, only get them when a rule needs them
+            let with_tags = state_clone.rules.uses_tags();
+            for (bvid, result) in bvids.iter().zip(results) {
+                let tags = match &result {
+                    Ok(_) if with_tags => fetch_tags(fetcher_clone.as_ref(), bvid, stats).await,
+                    _ => None,
+                };
+                finish_bv(&state_clone, bvid, result, tags).await;
+            }
+        });
+    }
+
+    // Shutting down: let in-flight requests finish, then persist what is left
+    queue.close();
+    tracker.close();
+    if tokio::time::timeout(DRAIN_TIMEOUT, tracker.wait()).await.is_err() {
+        write_log("Spider requests still running at shutdown deadline");
+    }
+    persist_queue(&state).await;
+}
+
+async fn fetch_tags(fetcher: &dyn MetadataFetcher, bvid: &str, stats: &SpiderStats) -> Option<Vec<String>> {
+    match fetcher.fetch_tags(bvid, stats).await {
+        Ok(tags) => Some(tags),
+        Err(FetchError::Unsupported) => None,
+        Err(e) => {
+            write_log(&format!("Failed to get tags of {}: {}", bvid, e));
+            None
+        }
+    }
+}
+
+/// Stores the result for one BV and takes it off the queue.
+async fn finish_bv(state: &AppState, bvid: &str, result: Result<VideoMeta, FetchError>, tags: Option<Vec<String>>) {
+    let mut success = false;
+    match result {
+        Ok(meta) => {
+            let mid = meta.owner_mid;
+            let info = VideoInfo {
+                title: meta.title,
+                tid: meta.tid,
+                tname: meta.tname,
+                duration: meta.duration,
+                pubdate: meta.pubdate,
+                tags,
+            };
+            let conn = state.db.write().await;
+            // Update cache
+            if db::cache_video(&conn, bvid, mid, &info).is_ok() {
+                state.index.cache_bv_mid(bvid, mid);
+                state.spider_stats.bv_cache_count.fetch_add(1, Ordering::Relaxed);
+                success = true;
+            }
+            // The view response names the owner, which saves a profile lookup
+            if let Some(name) = meta.owner_name.as_deref().filter(|_| state.index.is_blocked(mid)) {
+                backfill_username(&conn, mid, name);
+            }
+        }
+        Err(e) => write_log(&format!("Failed to resolve {}: {}", bvid, e)),
+    }
+
+    if !success {
+         state.spider_stats.fail_count.fetch_add(1, Ordering::Relaxed);
+    }
+    
+    // Remove from pending set so it can be requested again later
+    {
+        let mut pending = state.pending_bvs.lock().await;
+        pending.remove(bvid);
+    }
+
+    // Mark task as completed
+    state.spider_stats.queue_size.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// Takes BVs that were dropped from the queue without a fetch off the books.
+pub(crate) async fn skip_bvs(state: &AppState, bvids: &[String]) {
+    if bvids.is_empty() {
+        return;
+    }
+
+    let mut pending = state.pending_bvs.lock().await;
+    for bvid in bvids {
+        pending.remove(bvid);
+    }
+    let stats = &state.spider_stats;
+    stats.queue_size.fetch_sub(bvids.len(), Ordering::Relaxed);
+    stats.skipped_count.fetch_add(bvids.len(), Ordering::Relaxed);
+}
+
+/// What is known about a mid's profile
+#[derive(Debug, Serialize)]
+pub struct ProfileLookup {
+    /// Last fetched profile, possibly older than `PROFILE_TTL`
+    pub profile: Option<UserProfile>,
+    /// A fresh lookup has been requested from the spider
+    pub pending: bool,
+}
+
+/// The cached profile of `mid`. A missing or stale one is requested from the
+/// spider, so asking again later returns a fresh one.
+pub async fn lookup_profile(state: &AppState, mid: i64) -> rusqlite::Result<ProfileLookup> {
+    let profile = {
+        let conn = state.db.read().await;
+        db::get_user_profile(&conn, mid)?
+    };
+
+    let pending = profile.as_ref().is_none_or(is_stale);
+    if pending {
+        request_profiles(state, [mid]);
+    }
+    Ok(ProfileLookup { profile, pending })
+}
+
+/// Whether `profile` is older than `PROFILE_TTL`
+pub fn is_stale(profile: &UserProfile) -> bool {
+    profile.updated_at < chrono::Utc::now().timestamp() - PROFILE_TTL.as_secs() as i64
+}
+
+/// Has the spider look up these mids' profiles soon. Requests beyond
+/// `PROFILE_REQUEST_LIMIT` outstanding ones are dropped; they are asked for
+/// again the next time they are needed.
+pub fn request_profiles(state: &AppState, mids: impl IntoIterator<Item = i64>) {
+    let mut requests = state.profile_requests.lock().unwrap();
+    for mid in mids {
+        if requests.len() >= PROFILE_REQUEST_LIMIT {
+            break;
+        }
+        requests.insert(mid);
+    }
+    drop(requests);
+    state.profile_wakeup.notify_one();
+}
+
+/// Has the spider get the tags of these cached videos soon, capped like
+/// `request_profiles`.
+pub fn request_tags<'a>(state: &AppState, bvids: impl IntoIterator<Item = &'a str>) {
+    let mut requests = state.tag_requests.lock().unwrap();
+    for bvid in bvids {
+        if requests.len() >= PROFILE_REQUEST_LIMIT {
+            break;
+        }
+        requests.insert(bvid.to_string());
+    }
+    drop(requests);
+    state.profile_wakeup.notify_one();
+}
+
+/// Looks up requested profiles, and those of blocked users saved without a
+/// name, then requested tags, a few at a time.
+async fn profile_worker(state: Arc<AppState>, fetcher: Arc<dyn MetadataFetcher>) {
+    let shutdown = &state.shutdown_token;
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(PROFILE_INTERVAL) => {}
+            _ = state.profile_wakeup.notified() => {}
+            _ = shutdown.cancelled() => break,
+        }
+        if state.spider_stats.is_paused.load(Ordering::Relaxed) {
+            continue;
+        }
+
+        let mut mids: Vec<i64> = state.profile_requests.lock().unwrap().drain().collect();
+        {
+            let conn = state.db.read().await;
+            match db::users_needing_profile(&conn, PROFILE_TTL.as_secs() as i64, PROFILE_BATCH) {
+                Ok(unnamed) => {
+                    for mid in unnamed {
+                        if !mids.contains(&mid) {
+                            mids.push(mid);
                         }
                     }
                 }
-                Err(e) => {
-                    write_log(&format!("Network error for {}: {}", bvid_clone, e));
-                }
+                Err(e) => write_log(&format!("Failed to list users without a name: {}", e)),
             }
+        }
 
-            if !success {
-                 state_clone.spider_stats.fail_count.fetch_add(1, Ordering::Relaxed);
+        for mid in mids {
+            refresh_profile(&state, fetcher.as_ref(), mid).await;
+            tokio::select! {
+                _ = tokio::time::sleep(PROFILE_DELAY) => {}
+                _ = shutdown.cancelled() => return,
             }
-            
-            // Remove from pending set so it can be requested again later
-            {
-                let mut pending = state_clone.pending_bvs.lock().await;
-                pending.remove(&bvid_clone);
+        }
+
+        let bvids: Vec<String> = state.tag_requests.lock().unwrap().drain().collect();
+        for bvid in bvids {
+            if let Some(tags) = fetch_tags(fetcher.as_ref(), &bvid, &state.spider_stats).await {
+                let conn = state.db.write().await;
+                if let Err(e) = db::set_video_tags(&conn, &bvid, &tags) {
+                    write_log(&format!("Failed to save tags of {}: {}", bvid, e));
+                }
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(PROFILE_DELAY) => {}
+                _ = shutdown.cancelled() => return,
             }
+        }
+    }
+}
 
-            // Mark task as completed
-            state_clone.spider_stats.queue_size.fetch_sub(1, Ordering::Relaxed);
-        });
+async fn refresh_profile(state: &AppState, fetcher: &dyn MetadataFetcher, mid: i64) {
+    let user = match fetcher.fetch_user(mid, &state.spider_stats).await {
+        Ok(user) => user,
+        Err(FetchError::Unsupported) => return,
+        // e.g. a deleted account: remember that instead of asking every round
+        Err(e) if e.is_permanent() => {
+            write_log(&format!("No profile for {}: {}", mid, e));
+            UserMeta {
+                name: None,
+                face: None,
+                fans: None,
+                level: None,
+                join_time: None,
+            }
+        }
+        Err(e) => return write_log(&format!("Failed to get profile of {}: {}", mid, e)),
+    };
+
+    let profile = UserProfile {
+        mid,
+        name: user.name,
+        face: user.face,
+        fans: user.fans,
+        level: user.level,
+        join_time: user.join_time,
+        updated_at: chrono::Utc::now().timestamp(),
+    };
+    let conn = state.db.write().await;
+    if let Err(e) = db::cache_user_profile(&conn, &profile) {
+        return write_log(&format!("Failed to save profile of {}: {}", mid, e));
+    }
+    if let Some(name) = &profile.name {
+        backfill_username(&conn, mid, name);
+    }
+}
+
+fn backfill_username(conn: &rusqlite::Connection, mid: i64, name: &str) {
+    match db::backfill_username(conn, mid, name) {
+        Ok(true) => write_log(&format!("Filled in name of blocked user {}: {}", mid, name)),
+        Ok(false) => {}
+        Err(e) => write_log(&format!("Failed to fill in name of {}: {}", mid, e)),
     }
 }
 
-fn build_client(proxy_url: &Option<String>, enabled: bool) -> Client {
-    let mut builder = Client::builder()
-        .user_agent("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36 Edg/120.0.0.0")
-        .pool_idle_timeout(std::time::Duration::from_secs(15))
-        .pool_max_idle_per_host(16);
+/// How long a fetched profile is used before it is looked up again
+const PROFILE_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// How often blocked users without a name are looked for
+const PROFILE_INTERVAL: Duration = Duration::from_secs(60);
+/// Most unnamed blocked users looked up per round
+const PROFILE_BATCH: usize = 20;
+/// Pause between profile lookups, which take two API requests each
+const PROFILE_DELAY: Duration = Duration::from_millis(500);
+/// Most profile lookups waiting to be made
+const PROFILE_REQUEST_LIMIT: usize = 500;
+
+/// How long the batch strategy waits for more BVs before sending a request
+const BATCH_WINDOW: Duration = Duration::from_millis(50);
+/// Most BVs handed to the fetcher at once
+const BATCH_SIZE: usize = 50;
+
+/// How often `MetadataFetcher::maintain` runs
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
 
-    if enabled {
-        if let Some(url) = proxy_url {
-            if !url.is_empty() {
-                 match Proxy::all(url) {
-                     Ok(proxy) => {
-                         builder = builder.proxy(proxy);
-                         write_log(&format!("Proxy set to: {}", url));
-                     },
-                     Err(e) => write_log(&format!("Invalid proxy url '{}': {}", url, e)),
-                 }
+/// How long in-flight API requests may take to finish on shutdown
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Re-queues BVs persisted by the previous shutdown.
+async fn restore_queue(state: &AppState) {
+    let restored = {
+        let mut conn = state.db.write().await;
+        match db::take_spider_queue(&mut conn, state.spider_queue.capacity()) {
+            Ok(bvids) => bvids,
+            Err(e) => {
+                write_log(&format!("Failed to restore spider queue: {}", e));
+                return;
             }
         }
+    };
+
+    let mut pending = state.pending_bvs.lock().await;
+    for bvid in restored {
+        if pending.insert(bvid.clone()) {
+            if state.spider_queue.try_push(bvid.clone(), Priority::Normal) {
+                state.spider_stats.queue_size.fetch_add(1, Ordering::Relaxed);
+            } else {
+                pending.remove(&bvid);
+            }
+        }
+    }
+}
+
+/// Everything still in pending_bvs was queued but never resolved.
+async fn persist_queue(state: &AppState) {
+    let bvids: Vec<String> = state.pending_bvs.lock().await.iter().cloned().collect();
+    if bvids.is_empty() {
+        return;
     }
 
-    builder.build().unwrap_or_else(|e| {
-        write_log(&format!("Failed to build client: {}", e));
-        Client::new()
-    })
+    let mut conn = state.db.write().await;
+    match db::save_spider_queue(&mut conn, &bvids) {
+        Ok(()) => write_log(&format!("Persisted {} queued BVs for next start", bvids.len())),
+        Err(e) => write_log(&format!("Failed to persist spider queue: {}", e)),
+    }
 }
@@ -1,9 +1,21 @@
-use std::sync::atomic::{AtomicBool, AtomicI8, AtomicU64, AtomicUsize};
+use std::sync::atomic::{AtomicBool, AtomicI8, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use rusqlite::Connection;
+use tokio::sync::{Mutex, Notify};
 use std::collections::HashSet;
-use std::time::Instant;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+use crate::config::ConfigManager;
+use crate::db;
+use crate::index::BlockIndex;
+use crate::paths::AppPaths;
+use crate::pool::DbPool;
+use crate::proxy::ProxyStat;
+use crate::queue::SpiderQueue;
+use crate::rules::RuleSet;
+use crate::server::Server;
 
 pub struct ServiceStats {
     pub req_count: AtomicUsize,
@@ -11,9 +23,12 @@ pub struct ServiceStats {
 }
 
 pub struct DbStats {
+    /// Recounted on every blocklist change. A temporary block that runs out
+    /// is counted until the cleaner's next purge, at most an hour later.
     pub blocked_user_count: AtomicUsize,
 }
 
+#[derive(Default)]
 pub struct SpiderStats {
     pub bv_cache_count: AtomicUsize,
     pub req_time_sum: AtomicU64,
@@ -23,23 +38,48 @@ pub struct SpiderStats {
     pub is_paused: AtomicBool,
     pub total_received_count: AtomicUsize,
     pub actual_api_req_count: AtomicUsize,
+    /// Snapshot of the fetcher's proxy pool, refreshed after each request
+    pub proxies: std::sync::Mutex<Vec<ProxyStat>>,
+    /// The configured SESSDATA was rejected by Bilibili
+    pub session_expired: AtomicBool,
+    /// BVs not queued, or evicted, because the queue was full
+    pub overflow_dropped: AtomicUsize,
+    /// BVs answered "Busy" because the queue was full
+    pub overflow_rejected: AtomicUsize,
+    /// Queued BVs never fetched because they expired or were cancelled
+    pub skipped_count: AtomicUsize,
 }
 
 pub struct AppState {
-    pub db_conn: Arc<Mutex<Connection>>,
+    pub db: DbPool,
+    pub index: BlockIndex,
+    pub rules: RuleSet,
     pub service_stats: ServiceStats,
     pub db_stats: DbStats,
     pub spider_stats: SpiderStats,
-    pub spider_queue: tokio::sync::mpsc::Sender<String>,
+    pub spider_queue: SpiderQueue,
     pub pending_bvs: Mutex<HashSet<String>>,
+    /// Mids whose profile was asked for, looked up by the spider
+    pub profile_requests: std::sync::Mutex<HashSet<i64>>,
+    /// Cached BVs whose tags were asked for, fetched with the profiles
+    pub tag_requests: std::sync::Mutex<HashSet<String>>,
+    /// Wakes the spider's profile lookups early
+    pub profile_wakeup: Notify,
     pub start_time: Instant,
     pub server_status: AtomicI8, // 0: Init, 1: Running, 2: Failed/Occupied
+    pub server: std::sync::Mutex<Option<Server>>,
+    /// Cancelled when the app is shutting down
+    pub shutdown_token: CancellationToken,
+    /// Long-running background tasks (spider, cleaner) awaited on shutdown
+    pub tasks: TaskTracker,
 }
 
 impl AppState {
-    pub fn new(db_conn: Connection, spider_tx: tokio::sync::mpsc::Sender<String>) -> Self {
+    pub fn new(db: DbPool, index: BlockIndex, rules: RuleSet, spider_queue: SpiderQueue) -> Self {
         Self {
-            db_conn: Arc::new(Mutex::new(db_conn)),
+            db,
+            index,
+            rules,
             service_stats: ServiceStats {
                 req_count: AtomicUsize::new(0),
                 req_time_sum: AtomicU64::new(0),
@@ -47,20 +87,101 @@ impl AppState {
             db_stats: DbStats {
                 blocked_user_count: AtomicUsize::new(0),
             },
-            spider_stats: SpiderStats {
-                bv_cache_count: AtomicUsize::new(0),
-                req_time_sum: AtomicU64::new(0),
-                fail_count: AtomicUsize::new(0),
-                queue_size: AtomicUsize::new(0),
-                session_cleaned_count: AtomicUsize::new(0),
-                is_paused: AtomicBool::new(false),
-                total_received_count: AtomicUsize::new(0),
-                actual_api_req_count: AtomicUsize::new(0),
-            },
-            spider_queue: spider_tx,
+            spider_stats: SpiderStats::default(),
+            spider_queue,
             pending_bvs: Mutex::new(HashSet::new()),
+            profile_requests: std::sync::Mutex::new(HashSet::new()),
+            tag_requests: std::sync::Mutex::new(HashSet::new()),
+            profile_wakeup: Notify::new(),
             start_time: Instant::now(),
             server_status: AtomicI8::new(0),
+            server: std::sync::Mutex::new(None),
+            shutdown_token: CancellationToken::new(),
+            tasks: TaskTracker::new(),
+        }
+    }
+
+    /// Recounts the blocked users after the blocklist changed. Counting from
+    /// the table keeps expired blocks and disabled lists out.
+    pub fn recount_blocked_users(&self, conn: &rusqlite::Connection) {
+        if let Ok(count) = db::get_blocked_count(conn) {
+            self.db_stats.blocked_user_count.store(count, Ordering::Relaxed);
         }
     }
+
+    /// Stops the HTTP server, lets the spider drain and persist its queue,
+    /// then closes the database connection.
+    pub async fn shutdown(&self) {
+        self.shutdown_within(SHUTDOWN_TIMEOUT).await;
+    }
+
+    /// `shutdown`, giving background tasks `timeout` to finish. Tasks still
+    /// running after that may yet use the database, so it is left open for
+    /// the process exit to drop.
+    pub async fn shutdown_within(&self, timeout: Duration) {
+        // Stop accepting requests and wait for in-flight ones
+        let server = self.server.lock().unwrap().take();
+        if let Some(server) = server {
+            server.stop().await;
+        }
+
+        self.shutdown_token.cancel();
+        self.tasks.close();
+        if tokio::time::timeout(timeout, self.tasks.wait()).await.is_err() {
+            eprintln!("Background tasks did not finish within {:?}, leaving the database open", timeout);
+            return;
+        }
+
+        self.db.close().await;
+    }
+}
+
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Read connections available to HTTP handlers
+const DB_READERS: usize = 4;
+
+/// BVs waiting for the spider
+const SPIDER_QUEUE_CAPACITY: usize = 1000;
+
+/// BV → mid entries kept in memory in front of bv_cache
+const BV_INDEX_CAPACITY: usize = 100_000;
+
+/// Prepares the data directory, then opens the database and config in it.
+pub fn init(paths: &AppPaths) -> (Arc<AppState>, Arc<ConfigManager>) {
+    if let Err(e) = paths.create_dirs() {
+        eprintln!("Failed to create data directories: {}", e);
+    }
+    paths.migrate_legacy();
+
+    open(paths.db_file(), paths.config_file())
+}
+
+/// Opens the database and config, and builds the shared state.
+pub fn open<P: AsRef<Path>, Q: AsRef<Path>>(db_file: P, config_file: Q) -> (Arc<AppState>, Arc<ConfigManager>) {
+    // Initialize DB
+    let pool = DbPool::open(db_file, DB_READERS).expect("Failed to init DB");
+
+    // Initialize Config
+    let config_manager = Arc::new(ConfigManager::new(config_file));
+
+    // Initial stats load
+    let (blocked_count, cache_count, index, rules) = {
+        let conn = pool.try_write().expect("DB writer is free at startup");
+        (
+            db::get_blocked_count(&conn).unwrap_or(0),
+            db::get_bv_cache_count(&conn).unwrap_or(0),
+            BlockIndex::load(&conn, BV_INDEX_CAPACITY).expect("Failed to load blocklist"),
+            RuleSet::load(&conn).expect("Failed to load rules"),
+        )
+    };
+
+    let spider_queue = SpiderQueue::new(SPIDER_QUEUE_CAPACITY);
+    spider_queue.set_overflow_policy(config_manager.get_config().queue_overflow);
+    let app_state = Arc::new(AppState::new(pool, index, rules, spider_queue));
+
+    app_state.db_stats.blocked_user_count.store(blocked_count, Ordering::Relaxed);
+    app_state.spider_stats.bv_cache_count.store(cache_count, Ordering::Relaxed);
+
+    (app_state, config_manager)
 }
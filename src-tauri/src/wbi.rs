@@ -0,0 +1,97 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Order in which characters of `img_key + sub_key` form the mixin key
+const MIXIN_KEY_ENC_TAB: [usize; 64] = [
+    46, 47, 18, 2, 53, 8, 23, 32, 15, 50, 10, 31, 58, 3, 45, 35, 27, 43, 5, 49, 33, 9, 42, 19, 29, 28, 14, 39, 12,
+    38, 41, 13, 37, 48, 7, 16, 24, 55, 40, 61, 26, 17, 0, 1, 60, 51, 30, 4, 22, 25, 54, 21, 56, 59, 6, 63, 57, 62,
+    11, 36, 20, 34, 44, 52,
+];
+
+/// Characters Bilibili strips from parameter values before signing
+const FILTERED_CHARS: &[char] = &['!', '\'', '(', ')', '*'];
+
+/// The keys rotate daily; refetch well before that
+const KEY_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Key name from a `wbi_img` URL such as
+/// `https://i0.hdslb.com/bfs/wbi/7cd084941338484aae1ad9425b84077c.png`.
+pub fn key_from_url(url: &str) -> Option<String> {
+    let file = url.rsplit('/').next()?;
+    let key = file.split('.').next()?;
+    if key.is_empty() {
+        None
+    } else {
+        Some(key.to_string())
+    }
+}
+
+pub fn mixin_key(img_key: &str, sub_key: &str) -> String {
+    let raw: Vec<char> = format!("{}{}", img_key, sub_key).chars().collect();
+    MIXIN_KEY_ENC_TAB
+        .iter()
+        .filter_map(|&i| raw.get(i))
+        .take(32)
+        .collect()
+}
+
+/// Query string for `params` with `wts` and `w_rid` appended, ready to put
+/// after the `?` of a WBI endpoint.
+pub fn sign(params: &[(&str, String)], mixin_key: &str, wts: u64) -> String {
+    let mut pairs: Vec<(String, String)> = params
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.replace(FILTERED_CHARS, "")))
+        .collect();
+    pairs.push(("wts".to_string(), wts.to_string()));
+    pairs.sort();
+
+    let query = pairs
+        .iter()
+        .map(|(key, value)| format!("{}={}", encode(key), encode(value)))
+        .collect::<Vec<_>>()
+        .join("&");
+    let w_rid = md5::compute(format!("{}{}", query, mixin_key));
+    format!("{}&w_rid={:x}", query, w_rid)
+}
+
+/// Current Unix time, the usual `wts`.
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Percent-encoding matching JavaScript's `encodeURIComponent` once the
+/// filtered characters are gone.
+fn encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Mixin key fetched from the nav endpoint, kept for `KEY_TTL`.
+#[derive(Default)]
+pub struct KeyCache {
+    key: Mutex<Option<(String, Instant)>>,
+}
+
+impl KeyCache {
+    pub fn get(&self) -> Option<String> {
+        match &*self.key.lock().unwrap() {
+            Some((key, fetched_at)) if fetched_at.elapsed() < KEY_TTL => Some(key.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn set(&self, mixin_key: String) {
+        *self.key.lock().unwrap() = Some((mixin_key, Instant::now()));
+    }
+
+    /// Forgets the key, e.g. after Bilibili rejected a signature made with it.
+    pub fn invalidate(&self) {
+        *self.key.lock().unwrap() = None;
+    }
+}
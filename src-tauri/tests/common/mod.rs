@@ -0,0 +1,302 @@
+//! Shared helpers for the integration tests: a temporary app state, a mock of
+//! the Bilibili API and an in-memory metadata fetcher.
+
+#![allow(dead_code)]
+
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use async_trait::async_trait;
+use fuckbilibili_lib::config::ConfigManager;
+use fuckbilibili_lib::fetcher::{FetchError, MetadataFetcher, VideoMeta};
+use fuckbilibili_lib::queue::Priority;
+use fuckbilibili_lib::spider;
+use fuckbilibili_lib::state::{self, AppState, SpiderStats};
+use serde::Deserialize;
+use serde_json::{json, Map};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tempfile::TempDir;
+
+pub struct TestApp {
+    // Removed when the test ends
+    pub dir: TempDir,
+    pub state: Arc<AppState>,
+    pub config: Arc<ConfigManager>,
+}
+
+/// App state backed by a fresh database and config in a temp directory
+pub fn test_app() -> TestApp {
+    let dir = tempfile::tempdir().unwrap();
+    spider::set_log_dir(dir.path().join("log"));
+    let (state, config) = state::open(dir.path().join("blocked_users.db"), dir.path().join("config.json"));
+    TestApp { dir, state, config }
+}
+
+impl TestApp {
+    /// Starts the spider with the given fetcher on the current runtime
+    pub fn start_spider(&self, fetcher: Arc<dyn MetadataFetcher>) {
+        let task = spider::start_spider(self.state.clone(), self.config.clone(), fetcher);
+        tokio::spawn(self.state.tasks.track_future(task));
+    }
+
+    /// Queues BVs the same way /isBlockedBVS does
+    pub async fn enqueue(&self, bvids: &[&str]) {
+        self.enqueue_with(bvids, Priority::Normal).await;
+    }
+
+    pub async fn enqueue_with(&self, bvids: &[&str], priority: Priority) {
+        for bvid in bvids {
+            self.state.pending_bvs.lock().await.insert(bvid.to_string());
+            assert!(self.state.spider_queue.push(bvid.to_string(), priority).await);
+            self.state.spider_stats.queue_size.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Waits until the spider has worked through everything queued
+    pub async fn wait_idle(&self) {
+        wait_until(|| self.state.spider_stats.queue_size.load(Ordering::Relaxed) == 0).await;
+    }
+}
+
+pub async fn wait_until(mut done: impl FnMut() -> bool) {
+    for _ in 0..250 {
+        if done() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("condition not reached within 5s");
+}
+
+/// Mock owner mid: the digits of the BV, e.g. BV12 is owned by mid 12
+pub fn owner_of(bvid: &str) -> i64 {
+    bvid.trim_start_matches("BV").parse().unwrap_or(0)
+}
+
+/// In-memory fetcher: BVs listed in `api_errors` fail with that code, every
+/// other BV is owned by `owner_of(bvid)`.
+#[derive(Default)]
+pub struct StaticFetcher {
+    pub api_errors: HashMap<String, i32>,
+    pub calls: AtomicUsize,
+    /// BVs in the order they were fetched
+    pub fetched: Mutex<Vec<String>>,
+}
+
+#[async_trait]
+impl MetadataFetcher for StaticFetcher {
+    async fn fetch(&self, bvid: &str, stats: &SpiderStats) -> Result<VideoMeta, FetchError> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        self.fetched.lock().unwrap().push(bvid.to_string());
+        stats.actual_api_req_count.fetch_add(1, Ordering::Relaxed);
+        if let Some(code) = self.api_errors.get(bvid) {
+            return Err(FetchError::Api(*code));
+        }
+        Ok(VideoMeta {
+            owner_mid: owner_of(bvid),
+            title: Some(format!("video {}", bvid)),
+            ..VideoMeta::default()
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum CardsMode {
+    /// Returns a card for every id except "BV404"
+    Ok,
+    /// Responds with a non-zero code
+    ApiError,
+    /// Responds with a body that is not JSON
+    Garbage,
+    /// Responds with code -412
+    Throttled,
+}
+
+/// Mock of the Bilibili endpoints the spider uses. Videos are in partition
+/// 17 and as many seconds long as their owner's mid. For the view endpoint
+/// "BV404" answers code -404, "BV412" answers -412, "BV101" answers -101 and
+/// "BVgarbage" answers a non-JSON body. The nav endpoint reports a login only
+/// for the cookie `SESSDATA=valid`, and hands out the WBI keys from
+/// Bilibili's documentation, which `/x/space/wbi/acc/info` checks signatures
+/// against. Users are named after their mid, except 404 which does not exist.
+///
+/// It also accepts requests in proxy form, so its URL can be used as an HTTP
+/// proxy in front of any base URL.
+pub struct MockApi {
+    pub base_url: String,
+    pub cards_hits: AtomicUsize,
+    pub view_hits: AtomicUsize,
+    pub tag_hits: AtomicUsize,
+    /// Proxy-Authorization header of the last nav request
+    pub proxy_auth: Mutex<Option<String>>,
+    /// Cookie header of the last view or nav request
+    pub cookie: Mutex<Option<String>>,
+    pub nav_hits: AtomicUsize,
+    /// Makes the next signed request fail with -352 whatever its signature
+    pub reject_next_signature: AtomicBool,
+    cards_mode: CardsMode,
+}
+
+#[derive(Deserialize)]
+struct CardsQuery {
+    ids: String,
+}
+
+#[derive(Deserialize)]
+struct ViewQuery {
+    bvid: String,
+}
+
+async fn cards(query: web::Query<CardsQuery>, api: web::Data<Arc<MockApi>>) -> HttpResponse {
+    api.cards_hits.fetch_add(1, Ordering::SeqCst);
+    match api.cards_mode {
+        CardsMode::Ok => {
+            let mut data = Map::new();
+            for id in query.ids.split(',').filter(|id| *id != "BV404") {
+                data.insert(
+                    id.to_string(),
+                    json!({ "bvid": id, "title": "card", "owner": { "mid": owner_of(id), "name": "up" } }),
+                );
+            }
+            HttpResponse::Ok().json(json!({ "code": 0, "data": data }))
+        }
+        CardsMode::ApiError => HttpResponse::Ok().json(json!({ "code": -400, "data": null })),
+        CardsMode::Garbage => HttpResponse::Ok().body("<html>busy</html>"),
+        CardsMode::Throttled => HttpResponse::Ok().json(json!({ "code": -412, "data": null })),
+    }
+}
+
+fn header(req: &HttpRequest, name: &str) -> Option<String> {
+    req.headers().get(name).and_then(|h| h.to_str().ok()).map(str::to_string)
+}
+
+async fn view(req: HttpRequest, query: web::Query<ViewQuery>, api: web::Data<Arc<MockApi>>) -> HttpResponse {
+    api.view_hits.fetch_add(1, Ordering::SeqCst);
+    *api.cookie.lock().unwrap() = header(&req, "cookie");
+    match query.bvid.as_str() {
+        "BV404" => HttpResponse::Ok().json(json!({ "code": -404, "data": null })),
+        "BV412" => HttpResponse::Ok().json(json!({ "code": -412, "data": null })),
+        "BV101" => HttpResponse::Ok().json(json!({ "code": -101, "data": null })),
+        "BVgarbage" => HttpResponse::Ok().body("<html>busy</html>"),
+        bvid => HttpResponse::Ok().json(json!({
+            "code": 0,
+            "data": {
+                "title": "view",
+                "owner": { "mid": owner_of(bvid), "name": "up" },
+                "tid": 17,
+                "tname": "单机游戏",
+                "duration": owner_of(bvid),
+                "pubdate": 1700000000
+            }
+        })),
+    }
+}
+
+/// Mixin key of the documented example keys
+pub const MOCK_MIXIN_KEY: &str = "ea1db124af3c7062474693fa704f4ff8";
+
+async fn nav(req: HttpRequest, api: web::Data<Arc<MockApi>>) -> HttpResponse {
+    api.nav_hits.fetch_add(1, Ordering::SeqCst);
+    *api.proxy_auth.lock().unwrap() = header(&req, "proxy-authorization");
+    let cookie = header(&req, "cookie");
+    let logged_in = cookie.as_deref().is_some_and(|c| c.contains("SESSDATA=valid"));
+    *api.cookie.lock().unwrap() = cookie;
+    let wbi_img = json!({
+        "img_url": "https://i0.hdslb.com/bfs/wbi/7cd084941338484aae1ad9425b84077c.png",
+        "sub_url": "https://i0.hdslb.com/bfs/wbi/4932caff0ff746eab6f01bf08b70ac45.png"
+    });
+    if logged_in {
+        HttpResponse::Ok().json(json!({ "code": 0, "data": { "isLogin": true, "wbi_img": wbi_img } }))
+    } else {
+        HttpResponse::Ok().json(json!({ "code": -101, "data": { "isLogin": false, "wbi_img": wbi_img } }))
+    }
+}
+
+/// Answers -352 unless the query is sorted and `w_rid` matches
+async fn signed(req: HttpRequest, api: web::Data<Arc<MockApi>>) -> HttpResponse {
+    let (mut signed, mut w_rid) = (Vec::new(), None);
+    for pair in req.query_string().split('&') {
+        match pair.strip_prefix("w_rid=") {
+            Some(value) => w_rid = Some(value.to_string()),
+            None => signed.push(pair),
+        }
+    }
+    let sorted = signed.windows(2).all(|w| w[0] <= w[1]);
+    let expected = format!("{:x}", md5::compute(format!("{}{}", signed.join("&"), MOCK_MIXIN_KEY)));
+
+    if api.reject_next_signature.swap(false, Ordering::SeqCst) || !sorted || w_rid.as_deref() != Some(expected.as_str()) {
+        return HttpResponse::Ok().json(json!({ "code": -352, "message": "风控校验失败", "data": null }));
+    }
+    // Mid 404 is a deleted account, any other is named after its number
+    let mid = signed.iter().find_map(|pair| pair.strip_prefix("mid=")).unwrap_or("0");
+    if mid == "404" {
+        return HttpResponse::Ok().json(json!({ "code": -404, "data": null }));
+    }
+    HttpResponse::Ok().json(json!({
+        "code": 0,
+        "data": {
+            "query": signed.join("&"),
+            "name": format!("user{}", mid),
+            "face": format!("https://i0.hdslb.com/bfs/face/{}.jpg", mid),
+            "level": 6
+        }
+    }))
+}
+
+/// Tags "抽奖" and "tag<owner>" for every video
+async fn tags(query: web::Query<ViewQuery>, api: web::Data<Arc<MockApi>>) -> HttpResponse {
+    api.tag_hits.fetch_add(1, Ordering::SeqCst);
+    let own = format!("tag{}", owner_of(&query.bvid));
+    HttpResponse::Ok().json(json!({ "code": 0, "data": [{ "tag_name": "抽奖" }, { "tag_name": own }] }))
+}
+
+#[derive(Deserialize)]
+struct RelationQuery {
+    vmid: i64,
+}
+
+/// Every mock user has ten times its mid in followers
+async fn relation_stat(query: web::Query<RelationQuery>) -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "code": 0, "data": { "mid": query.vmid, "follower": query.vmid * 10 } }))
+}
+
+/// Starts the mock API on a free port. Must be called inside an actix system.
+pub fn start_mock_api(cards_mode: CardsMode) -> Arc<MockApi> {
+    let listener = std::net::TcpListener::bind(("127.0.0.1", 0)).unwrap();
+    let api = Arc::new(MockApi {
+        base_url: format!("http://{}", listener.local_addr().unwrap()),
+        cards_hits: AtomicUsize::new(0),
+        view_hits: AtomicUsize::new(0),
+        tag_hits: AtomicUsize::new(0),
+        proxy_auth: Mutex::new(None),
+        cookie: Mutex::new(None),
+        nav_hits: AtomicUsize::new(0),
+        reject_next_signature: AtomicBool::new(false),
+        cards_mode,
+    });
+
+    let data = web::Data::new(api.clone());
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(data.clone())
+            .route("/x/article/cards", web::get().to(cards))
+            .route("/x/web-interface/view", web::get().to(view))
+            .route("/x/web-interface/nav", web::get().to(nav))
+            .route("/x/space/wbi/acc/info", web::get().to(signed))
+            .route("/x/relation/stat", web::get().to(relation_stat))
+            .route("/x/tag/archive/tags", web::get().to(tags))
+    })
+    .workers(1)
+    .listen(listener)
+    .unwrap();
+    actix_web::rt::spawn(server.run());
+    api
+}
+
+/// Base URL of a port that was just free: it is bound and released again,
+/// so connecting to it is refused.
+pub fn dead_endpoint() -> String {
+    let listener = std::net::TcpListener::bind(("127.0.0.1", 0)).unwrap();
+    format!("http://{}", listener.local_addr().unwrap())
+}
@@ -0,0 +1,56 @@
+//! The service the `--headless` flag runs without the Tauri window.
+
+use fuckbilibili_lib::db;
+use fuckbilibili_lib::headless;
+use fuckbilibili_lib::paths::AppPaths;
+use std::net::TcpListener;
+use std::thread;
+use std::time::Duration;
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+#[tokio::test]
+async fn headless_service_serves_until_shut_down() {
+    let root = tempfile::tempdir().unwrap();
+    let paths = AppPaths {
+        data_dir: root.path().join("data"),
+        log_dir: root.path().join("data/log"),
+    };
+    let db_file = paths.db_file();
+    let port = free_port();
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+
+    let service = thread::spawn(move || {
+        headless::run_until(&paths, ("127.0.0.1", port), async {
+            let _ = stopped.await;
+        })
+    });
+
+    // The server is bound before the runtime starts, but give the thread time to get there
+    let base = format!("http://127.0.0.1:{}", port);
+    // No idle keep-alive connections, or the graceful stop waits them out
+    let client = reqwest::Client::builder().pool_max_idle_per_host(0).build().unwrap();
+    let mut alive = false;
+    for _ in 0..100 {
+        if let Ok(resp) = client.get(format!("{}/ok", base)).send().await {
+            assert_eq!(resp.text().await.unwrap(), "OK");
+            alive = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert!(alive, "headless server never answered");
+
+    let resp = client.post(format!("{}/block", base)).form(&[("mid", "42")]).send().await.unwrap();
+    assert_eq!(resp.text().await.unwrap(), "OK");
+
+    stop.send(()).unwrap();
+    tokio::task::spawn_blocking(move || service.join().unwrap()).await.unwrap();
+    assert!(client.get(format!("{}/ok", base)).send().await.is_err());
+
+    // The block went to the database in the data directory
+    let conn = db::init_db(&db_file).unwrap();
+    assert!(db::is_user_exist(&conn, 42).unwrap());
+}
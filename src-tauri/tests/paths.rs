@@ -0,0 +1,82 @@
+//! Moving files older versions kept in the working directory.
+
+use fuckbilibili_lib::db;
+use fuckbilibili_lib::paths::AppPaths;
+use std::fs;
+use std::path::Path;
+
+fn app_paths(root: &Path) -> AppPaths {
+    let paths = AppPaths {
+        data_dir: root.join("data"),
+        log_dir: root.join("data/log"),
+    };
+    paths.create_dirs().unwrap();
+    paths
+}
+
+fn legacy_dir(root: &Path, mids: &[i64]) -> std::path::PathBuf {
+    let dir = root.join("legacy");
+    fs::create_dir_all(&dir).unwrap();
+    let conn = db::init_db(dir.join("blocked_users.db")).unwrap();
+    for mid in mids {
+        db::add_user(&conn, *mid, None).unwrap();
+    }
+    dir
+}
+
+fn blocked(path: &Path) -> Vec<i64> {
+    let conn = db::init_db(path).unwrap();
+    db::list_users(&conn).unwrap().into_iter().map(|u| u.mid).collect()
+}
+
+#[test]
+fn legacy_database_replaces_an_empty_one_the_cli_created() {
+    let root = tempfile::tempdir().unwrap();
+    let legacy = legacy_dir(root.path(), &[1, 2]);
+    let paths = app_paths(root.path());
+
+    // The CLI ran first and created an empty database in the data directory
+    drop(db::init_db(paths.db_file()).unwrap());
+
+    // Then the GUI starts
+    paths.migrate_legacy_from(&legacy);
+    assert_eq!(blocked(&paths.db_file()), [1, 2]);
+    assert!(!legacy.join("blocked_users.db").exists());
+}
+
+#[test]
+fn legacy_database_never_replaces_one_with_any_data() {
+    let root = tempfile::tempdir().unwrap();
+    let legacy = legacy_dir(root.path(), &[1]);
+    let paths = app_paths(root.path());
+
+    // The blocklist was cleared, but rules and cached videos are kept
+    let conn = db::init_db(paths.db_file()).unwrap();
+    db::cache_bv_mid(&conn, "BV1", 1).unwrap();
+    drop(conn);
+    paths.migrate_legacy_from(&legacy);
+    assert!(blocked(&paths.db_file()).is_empty());
+    assert!(legacy.join("blocked_users.db").exists());
+
+    let conn = db::init_db(paths.db_file()).unwrap();
+    db::clean_expired_cache(&conn, -1).unwrap();
+    db::ensure_block_list(&conn, "spoilers").unwrap();
+    drop(conn);
+    paths.migrate_legacy_from(&legacy);
+    assert!(blocked(&paths.db_file()).is_empty());
+    assert!(legacy.join("blocked_users.db").exists());
+}
+
+#[test]
+fn legacy_database_never_replaces_one_with_users() {
+    let root = tempfile::tempdir().unwrap();
+    let legacy = legacy_dir(root.path(), &[1]);
+    let paths = app_paths(root.path());
+    let conn = db::init_db(paths.db_file()).unwrap();
+    db::add_user(&conn, 7, None).unwrap();
+    drop(conn);
+
+    paths.migrate_legacy_from(&legacy);
+    assert_eq!(blocked(&paths.db_file()), [7]);
+    assert!(legacy.join("blocked_users.db").exists());
+}
@@ -0,0 +1,137 @@
+//! Uploader profiles: fetching, caching, and filling in names of blocked users.
+
+mod common;
+
+use actix_web::test;
+use common::{start_mock_api, test_app, CardsMode, TestApp};
+use fuckbilibili_lib::config::AppConfig;
+use fuckbilibili_lib::db;
+use fuckbilibili_lib::fetcher::{BilibiliFetcher, FetchError, MetadataFetcher};
+use fuckbilibili_lib::server;
+use fuckbilibili_lib::state::SpiderStats;
+use serde_json::Value;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+async fn block(app: &TestApp, mid: i64, username: Option<&str>) {
+    let conn = app.state.db.write().await;
+    db::add_user(&conn, mid, username).unwrap();
+    app.state.index.add_blocked(mid);
+}
+
+async fn username(app: &TestApp, mid: i64) -> Option<String> {
+    let conn = app.state.db.read().await;
+    db::list_users(&conn).unwrap().into_iter().find(|u| u.mid == mid).and_then(|u| u.username)
+}
+
+async fn profile(app: &TestApp, mid: i64) -> Option<db::UserProfile> {
+    let conn = app.state.db.read().await;
+    db::get_user_profile(&conn, mid).unwrap()
+}
+
+async fn wait_for_profile(app: &TestApp, mid: i64) -> db::UserProfile {
+    for _ in 0..250 {
+        if let Some(profile) = profile(app, mid).await {
+            return profile;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("no profile for {} within 5s", mid);
+}
+
+#[actix_web::test]
+async fn fetcher_reads_profile_and_followers() {
+    let api = start_mock_api(CardsMode::Ok);
+    let fetcher = BilibiliFetcher::new(&api.base_url, &AppConfig::default());
+    let stats = SpiderStats::default();
+
+    let user = fetcher.fetch_user(7, &stats).await.unwrap();
+    assert_eq!(user.name.as_deref(), Some("user7"));
+    assert_eq!(user.face.as_deref(), Some("https://i0.hdslb.com/bfs/face/7.jpg"));
+    assert_eq!(user.fans, Some(70));
+    assert_eq!(user.level, Some(6));
+
+    let err = fetcher.fetch_user(404, &stats).await.unwrap_err();
+    assert!(matches!(err, FetchError::Api(-404)));
+    assert!(err.is_permanent());
+    assert!(!FetchError::Api(-412).is_permanent());
+}
+
+#[actix_web::test]
+async fn spider_fills_in_names_of_blocked_users() {
+    let api = start_mock_api(CardsMode::Ok);
+    let app = test_app();
+    block(&app, 5, None).await;
+    block(&app, 6, Some("kept")).await;
+    block(&app, 404, None).await;
+
+    app.start_spider(Arc::new(BilibiliFetcher::new(&api.base_url, &AppConfig::default())));
+    app.state.profile_wakeup.notify_one();
+
+    assert_eq!(wait_for_profile(&app, 5).await.fans, Some(50));
+    assert_eq!(username(&app, 5).await.as_deref(), Some("user5"));
+    // Users with a name are left alone
+    assert_eq!(username(&app, 6).await.as_deref(), Some("kept"));
+    assert!(profile(&app, 6).await.is_none());
+
+    // A deleted account is remembered rather than asked for every round
+    let gone = wait_for_profile(&app, 404).await;
+    assert_eq!(gone.name, None);
+    assert_eq!(username(&app, 404).await, None);
+    let conn = app.state.db.read().await;
+    assert!(db::users_needing_profile(&conn, 3600, 10).unwrap().is_empty());
+}
+
+#[actix_web::test]
+async fn spider_profile_lookups_are_signed_and_refresh_rotated_keys() {
+    let api = start_mock_api(CardsMode::Ok);
+    let app = test_app();
+    block(&app, 8, None).await;
+    // As if the keys rotated: the first signature is rejected with -352
+    api.reject_next_signature.store(true, Ordering::SeqCst);
+
+    app.start_spider(Arc::new(BilibiliFetcher::new(&api.base_url, &AppConfig::default())));
+    app.state.profile_wakeup.notify_one();
+
+    // The mock only answers correctly signed requests
+    assert_eq!(wait_for_profile(&app, 8).await.name.as_deref(), Some("user8"));
+    assert!(!api.reject_next_signature.load(Ordering::SeqCst));
+    assert!(api.nav_hits.load(Ordering::SeqCst) >= 2);
+}
+
+#[actix_web::test]
+async fn resolved_bvs_name_their_blocked_owner() {
+    let api = start_mock_api(CardsMode::Ok);
+    let app = test_app();
+    block(&app, 12, None).await;
+
+    app.start_spider(Arc::new(BilibiliFetcher::new(&api.base_url, &AppConfig::default())));
+    app.enqueue(&["BV12"]).await;
+    app.wait_idle().await;
+
+    assert_eq!(username(&app, 12).await.as_deref(), Some("up"));
+}
+
+#[actix_web::test]
+async fn user_profile_endpoint_fetches_in_background() {
+    let api = start_mock_api(CardsMode::Ok);
+    let app = test_app();
+    app.start_spider(Arc::new(BilibiliFetcher::new(&api.base_url, &AppConfig::default())));
+    let service = test::init_service(server::app(app.state.clone())).await;
+
+    let get = |uri: &str| test::TestRequest::get().uri(uri).to_request();
+    let body: Value = test::call_and_read_body_json(&service, get("/userProfile?mid=7")).await;
+    assert_eq!(body["msg"], "OK");
+    assert_eq!(body["pending"], true);
+    assert!(body["profile"].is_null());
+
+    wait_for_profile(&app, 7).await;
+    let body: Value = test::call_and_read_body_json(&service, get("/userProfile?mid=7")).await;
+    assert_eq!(body["pending"], false);
+    assert_eq!(body["profile"]["name"], "user7");
+    assert_eq!(body["profile"]["fans"], 70);
+
+    let body = test::call_and_read_body(&service, get("/userProfile?mid=abc")).await;
+    assert_eq!(body, "ERR1");
+}
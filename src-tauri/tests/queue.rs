@@ -0,0 +1,171 @@
+//! Spider queue ordering, capacity, cancellation and shutdown.
+
+mod common;
+
+use common::{test_app, StaticFetcher};
+use fuckbilibili_lib::config::{AppConfig, OverflowPolicy};
+use fuckbilibili_lib::queue::{Offer, Priority, SpiderQueue};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+async fn drain(queue: &SpiderQueue) -> Vec<String> {
+    let mut bvids = Vec::new();
+    while !queue.is_empty() {
+        bvids.push(queue.pop().await.unwrap().bvid);
+    }
+    bvids
+}
+
+#[tokio::test]
+async fn high_priority_first_and_newest_first() {
+    let queue = SpiderQueue::new(10);
+    for bvid in ["BV1", "BV2", "BV3"] {
+        assert!(queue.push(bvid.to_string(), Priority::Normal).await);
+    }
+    for bvid in ["BV4", "BV5"] {
+        assert!(queue.push(bvid.to_string(), Priority::High).await);
+    }
+
+    assert_eq!(drain(&queue).await, ["BV5", "BV4", "BV3", "BV2", "BV1"]);
+}
+
+#[tokio::test]
+async fn bump_moves_queued_bvs_up() {
+    let queue = SpiderQueue::new(10);
+    for bvid in ["BV1", "BV2", "BV3"] {
+        queue.push(bvid.to_string(), Priority::Normal).await;
+    }
+
+    assert!(queue.bump("BV1", Priority::Normal, None));
+    assert!(queue.bump("BV2", Priority::High, None));
+    assert!(!queue.bump("BV9", Priority::High, None));
+    // A high priority BV is never demoted
+    assert!(queue.bump("BV2", Priority::Normal, None));
+
+    assert_eq!(drain(&queue).await, ["BV2", "BV1", "BV3"]);
+}
+
+#[tokio::test]
+async fn push_waits_for_space() {
+    let queue = Arc::new(SpiderQueue::new(1));
+    queue.push("BV1".to_string(), Priority::Normal).await;
+    assert!(!queue.try_push("BV2".to_string(), Priority::High));
+
+    let waiting = tokio::spawn({
+        let queue = queue.clone();
+        async move { queue.push("BV2".to_string(), Priority::Normal).await }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!waiting.is_finished());
+
+    assert_eq!(queue.pop().await.map(|item| item.bvid).as_deref(), Some("BV1"));
+    assert!(waiting.await.unwrap());
+    assert_eq!(queue.pop().await.map(|item| item.bvid).as_deref(), Some("BV2"));
+}
+
+#[tokio::test]
+async fn close_drains_then_ends() {
+    let queue = Arc::new(SpiderQueue::new(10));
+    queue.push("BV1".to_string(), Priority::Normal).await;
+
+    let popper = tokio::spawn({
+        let queue = queue.clone();
+        async move { (queue.pop().await.map(|item| item.bvid), queue.pop().await.map(|item| item.bvid)) }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    queue.close();
+
+    assert_eq!(popper.await.unwrap(), (Some("BV1".to_string()), None));
+    assert!(!queue.push("BV2".to_string(), Priority::High).await);
+}
+
+#[actix_web::test]
+async fn spider_resolves_high_priority_first() {
+    let app = test_app();
+    let fetcher = Arc::new(StaticFetcher::default());
+    app.state.spider_stats.is_paused.store(true, Ordering::Relaxed);
+    app.start_spider(fetcher.clone());
+
+    app.enqueue(&["BV1", "BV2", "BV3"]).await;
+    app.enqueue_with(&["BV9"], Priority::High).await;
+    app.state.spider_stats.is_paused.store(false, Ordering::Relaxed);
+    app.wait_idle().await;
+
+    assert_eq!(*fetcher.fetched.lock().unwrap(), ["BV9", "BV3", "BV2", "BV1"]);
+}
+
+fn full_queue(policy: OverflowPolicy) -> SpiderQueue {
+    let queue = SpiderQueue::new(2);
+    queue.set_overflow_policy(policy);
+    assert_eq!(queue.offer("BV1", Priority::Normal, None), Offer::Queued);
+    assert_eq!(queue.offer("BV2", Priority::Normal, None), Offer::Queued);
+    queue
+}
+
+#[tokio::test]
+async fn overflow_drop_oldest_evicts_the_longest_waiting() {
+    let queue = full_queue(OverflowPolicy::DropOldest);
+
+    assert_eq!(queue.offer("BV3", Priority::Normal, None), Offer::Evicted("BV1".to_string()));
+    assert_eq!(queue.offer("BV4", Priority::High, None), Offer::Evicted("BV2".to_string()));
+    // Only high priority BVs left: a normal one cannot push them out
+    assert_eq!(queue.offer("BV5", Priority::Normal, None), Offer::Evicted("BV3".to_string()));
+    assert_eq!(queue.offer("BV6", Priority::High, None), Offer::Evicted("BV5".to_string()));
+    assert_eq!(queue.offer("BV7", Priority::Normal, None), Offer::Dropped);
+
+    assert_eq!(drain(&queue).await, ["BV6", "BV4"]);
+}
+
+#[tokio::test]
+async fn overflow_drop_newest_and_reject_keep_the_queue() {
+    let queue = full_queue(OverflowPolicy::DropNewest);
+    assert_eq!(queue.offer("BV3", Priority::High, None), Offer::Dropped);
+    assert_eq!(drain(&queue).await, ["BV2", "BV1"]);
+
+    let queue = full_queue(OverflowPolicy::Reject);
+    assert_eq!(queue.offer("BV3", Priority::High, None), Offer::Rejected);
+    assert_eq!(drain(&queue).await, ["BV2", "BV1"]);
+
+    queue.close();
+    assert_eq!(queue.offer("BV3", Priority::Normal, None), Offer::Closed);
+}
+
+#[tokio::test]
+async fn cancel_session_drops_bvs_only_it_wanted() {
+    let queue = SpiderQueue::new(10);
+    queue.offer("BV1", Priority::Normal, Some("tab1"));
+    queue.offer("BV2", Priority::Normal, Some("tab1"));
+    queue.offer("BV3", Priority::Normal, Some("tab2"));
+    queue.offer("BV4", Priority::Normal, Some("tab1"));
+    // Also wanted by another tab, or by a client without a session
+    queue.bump("BV2", Priority::Normal, Some("tab2"));
+    queue.bump("BV4", Priority::Normal, None);
+
+    assert_eq!(queue.cancel_session("tab1"), ["BV1"]);
+    assert!(queue.cancel_session("tab1").is_empty());
+    assert_eq!(drain(&queue).await, ["BV4", "BV2", "BV3"]);
+}
+
+#[actix_web::test]
+async fn spider_skips_expired_bvs() {
+    let app = test_app();
+    let fetcher = Arc::new(StaticFetcher::default());
+    app.state.spider_stats.is_paused.store(true, Ordering::Relaxed);
+    app.start_spider(fetcher.clone());
+
+    app.enqueue(&["BV1", "BV2"]).await;
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    app.enqueue(&["BV3"]).await;
+    let config = AppConfig {
+        queue_ttl_secs: 1,
+        ..AppConfig::default()
+    };
+    app.config.set_config(config).unwrap();
+    app.state.spider_stats.is_paused.store(false, Ordering::Relaxed);
+    app.wait_idle().await;
+
+    assert_eq!(*fetcher.fetched.lock().unwrap(), ["BV3"]);
+    assert_eq!(app.state.spider_stats.skipped_count.load(Ordering::Relaxed), 2);
+    assert!(app.state.pending_bvs.lock().await.is_empty());
+}
@@ -0,0 +1,476 @@
+//! Rule expressions on cached uploader profiles and video metadata.
+
+mod common;
+
+use actix_web::test::{call_and_read_body, call_and_read_body_json, call_service, init_service, TestRequest};
+use common::{start_mock_api, test_app, wait_until, CardsMode, TestApp};
+use fuckbilibili_lib::config::AppConfig;
+use fuckbilibili_lib::db::{self, UserProfile, VideoInfo};
+use fuckbilibili_lib::fetcher::BilibiliFetcher;
+use fuckbilibili_lib::expr::Expr;
+use fuckbilibili_lib::rules::{self, Candidate, DryRun, Rule, Subject};
+use fuckbilibili_lib::server;
+use serde_json::{json, Value};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+const NOW: i64 = 1_700_000_000;
+
+fn profile(mid: i64, level: i32, fans: i64) -> UserProfile {
+    UserProfile {
+        mid,
+        name: Some(format!("user{}", mid)),
+        face: None,
+        fans: Some(fans),
+        level: Some(level),
+        join_time: None,
+        updated_at: chrono::Utc::now().timestamp(),
+    }
+}
+
+fn uploader(profile: &UserProfile) -> Subject<'_> {
+    Subject {
+        profile: Some(profile),
+        video: None,
+    }
+}
+
+fn expr(source: &str) -> Expr {
+    Expr::parse(source).unwrap()
+}
+
+#[test]
+fn conditions_combine_with_and_or() {
+    let new_account = profile(1, 1, 10);
+    let popular = profile(2, 1, 10_000);
+
+    let and = expr("owner.level < 2 && owner.fans < 100");
+    assert!(and.matches(&uploader(&new_account), NOW));
+    assert!(!and.matches(&uploader(&popular), NOW));
+
+    let or = expr("owner.level >= 6 || owner.fans > 5000");
+    assert!(!or.matches(&uploader(&new_account), NOW));
+    assert!(or.matches(&uploader(&popular), NOW));
+
+    // && binds tighter than ||, parentheses and ! work as usual
+    let mixed = expr("owner.fans > 5000 || owner.level == 1 && owner.fans == 0");
+    assert!(!mixed.matches(&uploader(&new_account), NOW));
+    let grouped = expr("!(owner.fans > 5000 || owner.level == 1) && owner.name ~ \"USER\"");
+    assert!(!grouped.matches(&uploader(&new_account), NOW));
+    assert!(grouped.matches(&uploader(&profile(3, 4, 10)), NOW));
+}
+
+#[test]
+fn missing_profile_data_never_matches() {
+    let unknown = UserProfile {
+        fans: None,
+        ..profile(1, 6, 0)
+    };
+    assert!(!expr("owner.fans < 100").matches(&uploader(&unknown), NOW));
+    // Unknown stays unknown through negation, but a known half decides
+    assert!(!expr("!(owner.fans >= 100)").matches(&uploader(&unknown), NOW));
+    assert!(!expr("owner.fans < 100 && owner.level < 2").matches(&uploader(&unknown), NOW));
+    assert!(expr("owner.fans < 100 || owner.level == 6").matches(&uploader(&unknown), NOW));
+
+    // Account age only counts when Bilibili disclosed the registration time
+    let young = expr("owner.age_days < 30");
+    assert!(!young.matches(&uploader(&unknown), NOW));
+    let joined = UserProfile {
+        join_time: Some(NOW - 3 * 86_400),
+        ..unknown
+    };
+    assert!(young.matches(&uploader(&joined), NOW));
+}
+
+async fn cache_video(app: &TestApp, bvid: &str, profile: &UserProfile) {
+    let conn = app.state.db.write().await;
+    db::cache_bv_mid(&conn, bvid, profile.mid).unwrap();
+    db::cache_user_profile(&conn, profile).unwrap();
+}
+
+#[actix_web::test]
+async fn is_blocked_bvs_reports_the_matching_rule() {
+    let app = test_app();
+    let service = init_service(server::app(app.state.clone())).await;
+    cache_video(&app, "BV1", &profile(1, 0, 3)).await;
+    cache_video(&app, "BV2", &profile(2, 5, 3)).await;
+    {
+        let conn = app.state.db.write().await;
+        db::cache_bv_mid(&conn, "BV3", 3).unwrap();
+    }
+
+    let req = TestRequest::post()
+        .uri("/rules")
+        .set_json(json!({ "name": "new accounts", "expression": "owner.level < 2 && owner.fans < 100" }))
+        .to_request();
+    let saved: Rule = call_and_read_body_json(&service, req).await;
+    assert!(saved.id > 0);
+    assert!(saved.enabled);
+
+    let req = TestRequest::post().uri("/isBlockedBVS").set_form([("bvs", "BV1,BV2,BV3")]).to_request();
+    let body: Value = call_and_read_body_json(&service, req).await;
+    assert_eq!(body["result"], json!(["True", "False", "False"]));
+    assert_eq!(body["rule"], json!([saved.id, null, null]));
+    // BV3's uploader has no profile yet, the spider is asked for one
+    assert!(app.state.profile_requests.lock().unwrap().contains(&3));
+
+    // Disabled rules are kept but not applied
+    let req = TestRequest::post()
+        .uri("/rules")
+        .set_json(Rule {
+            enabled: false,
+            ..saved.clone()
+        })
+        .to_request();
+    call_service(&service, req).await;
+    let req = TestRequest::post().uri("/isBlockedBVS").set_form([("bvs", "BV1")]).to_request();
+    let body: Value = call_and_read_body_json(&service, req).await;
+    assert_eq!(body["result"], json!(["False"]));
+}
+
+#[actix_web::test]
+async fn rules_are_stored_and_deleted() {
+    let app = test_app();
+    let service = init_service(server::app(app.state.clone())).await;
+
+    let req = TestRequest::post()
+        .uri("/rules")
+        .set_json(json!({ "name": " ", "expression": "owner.fans < 1" }))
+        .to_request();
+    assert_eq!(call_service(&service, req).await.status(), 400);
+    let req = TestRequest::post()
+        .uri("/rules")
+        .set_json(json!({ "name": "broken", "expression": "owner.fans <" }))
+        .to_request();
+    assert_eq!(call_and_read_body(&service, req).await, "expected a value after the comparison at column 13");
+
+    let req = TestRequest::post()
+        .uri("/rules")
+        .set_json(json!({ "name": "quiet", "expression": " owner.fans == 0 " }))
+        .to_request();
+    let saved: Rule = call_and_read_body_json(&service, req).await;
+    assert_eq!(saved.expression, "owner.fans == 0");
+
+    let conn = app.state.db.read().await;
+    assert_eq!(db::list_rules(&conn).unwrap(), vec![saved.clone()]);
+    drop(conn);
+    let listed: Vec<Rule> = call_and_read_body_json(&service, TestRequest::get().uri("/rules").to_request()).await;
+    assert_eq!(listed, vec![saved.clone()]);
+
+    let delete = |id: i64| TestRequest::post().uri("/rules/delete").set_form([("id", id.to_string())]).to_request();
+    assert_eq!(call_and_read_body(&service, delete(saved.id)).await, "OK");
+    assert_eq!(call_and_read_body(&service, delete(saved.id)).await, "ERR2");
+    assert!(app.state.rules.list().is_empty());
+}
+
+fn video(tid: i32, duration: i64, tags: &[&str]) -> VideoInfo {
+    VideoInfo {
+        title: Some("video".to_string()),
+        tid: Some(tid),
+        tname: Some("单机游戏".to_string()),
+        duration: Some(duration),
+        pubdate: Some(NOW - 86_400),
+        tags: Some(tags.iter().map(|t| t.to_string()).collect()),
+    }
+}
+
+#[test]
+fn video_conditions_match_partition_duration_and_tags() {
+    let clip = video(17, 25, &["抽奖", "Minecraft"]);
+    let subject = Subject {
+        profile: None,
+        video: Some(&clip),
+    };
+
+    assert!(expr("tid == 17 && duration < 60 && age_days <= 1").matches(&subject, NOW));
+
+    assert!(expr(r#"tags ~ "minecraft""#).matches(&subject, NOW));
+    assert!(expr(r#"tags == "抽奖""#).matches(&subject, NOW));
+    assert!(!expr(r#"tags == "抽""#).matches(&subject, NOW));
+    assert!(!expr(r#"tags != "抽奖""#).matches(&subject, NOW));
+    assert!(expr(r#"tags != "新闻""#).matches(&subject, NOW));
+    assert!(expr("tags ~ /^mine/i").matches(&subject, NOW));
+    assert!(!expr("tags ~ /^mine/").matches(&subject, NOW));
+    assert!(expr("tags !~ /^[0-9]+$/").matches(&subject, NOW));
+
+    // No tags is an answer, tags never fetched are not
+    let untagged = video(17, 25, &[]);
+    let subject = Subject {
+        profile: None,
+        video: Some(&untagged),
+    };
+    assert!(expr(r#"tags != "抽奖""#).matches(&subject, NOW));
+    let unfetched = VideoInfo {
+        tags: None,
+        ..untagged
+    };
+    let subject = Subject {
+        profile: None,
+        video: Some(&unfetched),
+    };
+    assert!(!expr(r#"tags != "抽奖""#).matches(&subject, NOW));
+    assert!(!expr(r#"!(tags == "抽奖")"#).matches(&subject, NOW));
+
+    let tname = expr(r#"tname ~ "游戏""#);
+    assert!(tname.matches(&subject, NOW));
+    assert!(!tname.matches(&Subject::default(), NOW));
+    assert!(expr(r#"title ~ /^vid/ && title != "VIDEO 2""#).matches(&subject, NOW));
+}
+
+#[test]
+fn invalid_expressions_say_where() {
+    let error = |source: &str| Expr::parse(source).unwrap_err();
+    assert_eq!(error(""), "the expression is empty");
+    assert_eq!(error("owner.follows < 3"), "unknown field 'owner.follows' at column 1");
+    assert_eq!(error(r#"tags < "抽奖""#), "'tags' is text, only ==, !=, ~ and !~ apply at column 8");
+    assert_eq!(error("title == 3"), "'title' is compared with a string at column 10");
+    assert_eq!(error("duration ~ 3"), "'duration' is a number, '~' does not apply at column 12");
+    assert_eq!(error(r#"owner.fans < "100""#), "'owner.fans' is compared with a number at column 14");
+    assert_eq!(error("title == /x/"), "a regex needs '~' or '!~' at column 10");
+    assert_eq!(error("(tid == 1"), "'(' at column 1 is not closed");
+    assert_eq!(error("tid == 1 tid == 2"), "expected '&&', '||' or the end at column 10");
+    assert_eq!(error(r#"title ~ "抽奖"#), "unterminated string starting at column 9");
+    assert_eq!(error("title ~ /抽奖/x"), "unknown regex flag 'x' at column 13");
+    assert!(error("title ~ /(/").starts_with("invalid regex at column 9"));
+    assert_eq!(error("tid == 1 & tid == 2"), "unexpected '&' at column 10");
+}
+
+#[actix_web::test]
+async fn deeply_nested_and_overlong_expressions_are_rejected() {
+    let nested = |depth: usize| format!("{}tid == 1{}", "(".repeat(depth), ")".repeat(depth));
+    assert!(Expr::parse(&nested(64)).is_ok());
+    assert_eq!(
+        Expr::parse(&nested(65)).unwrap_err(),
+        "more than 64 levels of '!' and '(' at column 65"
+    );
+    assert_eq!(
+        Expr::parse(&format!("{}tid == 1", "!".repeat(100_000))).unwrap_err(),
+        "more than 64 levels of '!' and '(' at column 65"
+    );
+
+    let app = test_app();
+    let long = vec!["tid == 1"; 300].join(" || ");
+    let rule = Rule {
+        id: 0,
+        name: "long".to_string(),
+        enabled: true,
+        expression: long.clone(),
+    };
+    let error = rules::save_rule(&app.state, rule).await.unwrap_err();
+    assert_eq!(error, "the expression is longer than 2000 characters");
+    let error = rules::dry_run(&app.state, &Candidate::Expression(long)).await.unwrap_err();
+    assert_eq!(error, "the expression is longer than 2000 characters");
+    assert!(app.state.rules.list().is_empty());
+}
+
+#[test]
+fn literals_escape_quotes_and_slashes() {
+    let clip = VideoInfo {
+        title: Some(r#"say "hi" \ a/b"#.to_string()),
+        ..VideoInfo::default()
+    };
+    let subject = Subject {
+        profile: None,
+        video: Some(&clip),
+    };
+    assert!(expr(r#"title ~ "\"hi\" \\""#).matches(&subject, NOW));
+    assert!(expr(r"title ~ /a\/b$/").matches(&subject, NOW));
+    assert!(expr(r#"title ~ /"hi"\s/"#).matches(&subject, NOW));
+    assert!(expr("duration > -1 || title ~ \"say\"").matches(&subject, NOW));
+}
+
+#[actix_web::test]
+async fn is_blocked_bvs_applies_video_rules() {
+    let app = test_app();
+    let service = init_service(server::app(app.state.clone())).await;
+    {
+        let conn = app.state.db.write().await;
+        db::cache_video(&conn, "BV1", 1, &video(17, 30, &["抽奖"])).unwrap();
+        db::cache_video(&conn, "BV2", 2, &video(17, 600, &["攻略"])).unwrap();
+    }
+
+    let req = TestRequest::post()
+        .uri("/rules")
+        .set_json(json!({ "name": "giveaways", "expression": "tags ~ \"抽奖\" || title ~ /抽奖/" }))
+        .to_request();
+    let saved: Rule = call_and_read_body_json(&service, req).await;
+
+    let req = TestRequest::post().uri("/isBlockedBVS").set_form([("bvs", "BV1,BV2")]).to_request();
+    let body: Value = call_and_read_body_json(&service, req).await;
+    assert_eq!(body["result"], json!(["True", "False"]));
+    assert_eq!(body["rule"], json!([saved.id, null]));
+    // No rule looks at uploaders, so no profiles are fetched for them
+    assert!(app.state.profile_requests.lock().unwrap().is_empty());
+}
+
+#[actix_web::test]
+async fn spider_stores_video_metadata_and_tags_when_rules_need_them() {
+    let api = start_mock_api(CardsMode::Ok);
+    let app = test_app();
+    app.start_spider(Arc::new(BilibiliFetcher::new(&api.base_url, &AppConfig::default())));
+
+    app.enqueue(&["BV1"]).await;
+    app.wait_idle().await;
+    assert_eq!(api.tag_hits.load(Ordering::SeqCst), 0);
+
+    let rule = Rule {
+        id: 0,
+        name: "giveaways".to_string(),
+        enabled: true,
+        expression: r#"tags == "抽奖""#.to_string(),
+    };
+    rules::save_rule(&app.state, rule).await.unwrap();
+    app.enqueue(&["BV42"]).await;
+    app.wait_idle().await;
+
+    let conn = app.state.db.read().await;
+    let videos = db::get_videos(&conn, &["BV1", "BV42"]).unwrap();
+    assert_eq!(videos["BV1"].tid, Some(17));
+    assert_eq!(videos["BV1"].tname.as_deref(), Some("单机游戏"));
+    assert_eq!(videos["BV1"].tags, None);
+    assert_eq!(videos["BV42"].duration, Some(42));
+    assert_eq!(videos["BV42"].pubdate, Some(1_700_000_000));
+    assert_eq!(videos["BV42"].tags.as_deref(), Some(&["抽奖".to_string(), "tag42".to_string()][..]));
+}
+
+#[actix_web::test]
+async fn tags_of_videos_cached_before_a_tag_rule_are_fetched_when_checked() {
+    let api = start_mock_api(CardsMode::Ok);
+    let app = test_app();
+    app.start_spider(Arc::new(BilibiliFetcher::new(&api.base_url, &AppConfig::default())));
+    let service = init_service(server::app(app.state.clone())).await;
+
+    app.enqueue(&["BV7"]).await;
+    app.wait_idle().await;
+    let rule = Rule {
+        id: 0,
+        name: "giveaways".to_string(),
+        enabled: true,
+        expression: r#"tags == "抽奖""#.to_string(),
+    };
+    rules::save_rule(&app.state, rule).await.unwrap();
+
+    let check = || async {
+        let req = TestRequest::post().uri("/isBlockedBVS").set_form([("bvs", "BV7")]).to_request();
+        let body: Value = call_and_read_body_json(&service, req).await;
+        body["result"][0].clone()
+    };
+    assert_eq!(check().await, "False");
+    wait_until(|| api.tag_hits.load(Ordering::SeqCst) == 1).await;
+    let mut blocked = false;
+    for _ in 0..50 {
+        if check().await == "True" {
+            blocked = true;
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert!(blocked);
+}
+
+#[actix_web::test]
+async fn rules_stored_as_json_become_expressions() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("blocked_users.db");
+    let conn = db::init_db(&path).unwrap();
+    let legacy = [
+        json!({ "combinator": "or", "conditions": [
+            { "field": "level", "op": "<", "value": 2 },
+            { "field": "tag", "op": "contains", "value": "抽\"奖" }
+        ]}),
+        json!({ "conditions": [{ "field": "video_age_days", "op": ">", "value": 3 }] }),
+        json!({ "conditions": [{ "field": "shoe_size", "op": ">", "value": 3 }] }),
+    ];
+    for definition in &legacy {
+        conn.execute(
+            "INSERT INTO rules (name, definition) VALUES ('old', ?)",
+            [definition.to_string()],
+        )
+        .unwrap();
+    }
+    drop(conn);
+
+    let conn = db::init_db(&path).unwrap();
+    let expressions: Vec<String> = db::list_rules(&conn).unwrap().into_iter().map(|r| r.expression).collect();
+    assert_eq!(expressions[0], r#"owner.level < 2 || tags ~ "抽\"奖""#);
+    assert_eq!(expressions[1], "age_days > 3");
+    // Kept for the user to fix, but never applied
+    assert_eq!(expressions[2], legacy[2].to_string());
+    let set = rules::RuleSet::load(&conn).unwrap();
+    assert_eq!(set.list().len(), 3);
+    let clip = VideoInfo {
+        pubdate: Some(0),
+        ..VideoInfo::default()
+    };
+    let subject = Subject {
+        profile: None,
+        video: Some(&clip),
+    };
+    assert_eq!(set.first_match(&subject, NOW), Some(2));
+}
+
+async fn cache_videos(app: &TestApp, videos: &[(&str, i64, i64)]) {
+    let conn = app.state.db.write().await;
+    for (bvid, mid, duration) in videos {
+        db::cache_video(&conn, bvid, *mid, &video(17, *duration, &[])).unwrap();
+    }
+}
+
+fn sorted<T: Ord + Clone>(items: &[T]) -> Vec<T> {
+    let mut items = items.to_vec();
+    items.sort();
+    items
+}
+
+#[actix_web::test]
+async fn dry_run_reports_what_a_rule_would_hide() {
+    let app = test_app();
+    let service = init_service(server::app(app.state.clone())).await;
+    cache_videos(&app, &[("BV1", 1, 30), ("BV2", 1, 45), ("BV3", 2, 600), ("BV4", 3, 20), ("BV5", 4, 10)]).await;
+    {
+        let conn = app.state.db.write().await;
+        db::cache_bv_mid(&conn, "BVold", 9).unwrap();
+        db::add_user(&conn, 4, None).unwrap();
+        app.state.index.add_blocked(4);
+    }
+
+    let dry_run = |candidate: Value| TestRequest::post().uri("/dryRun").set_json(candidate).to_request();
+    let run: DryRun = call_and_read_body_json(&service, dry_run(json!({ "expression": "duration < 60" }))).await;
+    assert_eq!(run.checked, 6);
+    // BV5 is hidden by the blocklist already
+    assert_eq!(run.bvs, 3);
+    assert_eq!(run.uploaders, 2);
+    assert_eq!(sorted(&run.sample_bvs), ["BV1", "BV2", "BV4"]);
+    assert_eq!(sorted(&run.sample_uploaders), [1, 3]);
+
+    let resp = call_service(&service, dry_run(json!({ "expression": "duration <" }))).await;
+    assert_eq!(resp.status(), 400);
+
+    // Nothing is saved or hidden
+    assert!(app.state.rules.list().is_empty());
+    let req = TestRequest::post().uri("/isBlockedBVS").set_form([("bvs", "BV1")]).to_request();
+    let body: Value = call_and_read_body_json(&service, req).await;
+    assert_eq!(body["result"], json!(["False"]));
+}
+
+#[actix_web::test]
+async fn dry_run_reports_what_a_block_list_would_hide() {
+    let app = test_app();
+    cache_videos(&app, &[("BV1", 1, 30), ("BV2", 1, 45), ("BV3", 2, 600), ("BV4", 4, 10)]).await;
+    {
+        let conn = app.state.db.write().await;
+        db::add_user(&conn, 4, None).unwrap();
+        app.state.index.add_blocked(4);
+    }
+
+    let run = rules::dry_run(&app.state, &Candidate::Mids(vec![1, 4, 77])).await.unwrap();
+    assert_eq!(run.checked, 4);
+    assert_eq!(run.bvs, 2);
+    assert_eq!(run.uploaders, 1);
+    assert_eq!(sorted(&run.sample_bvs), ["BV1", "BV2"]);
+    assert_eq!(run.sample_uploaders, [1]);
+
+    let conn = app.state.db.read().await;
+    assert!(!db::is_user_exist(&conn, 1).unwrap());
+    assert!(!app.state.index.is_blocked(1));
+}
@@ -0,0 +1,439 @@
+//! End-to-end tests of the userscript HTTP API against a temporary database,
+//! with the spider resolving BVs through a mock Bilibili API.
+
+mod common;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::test;
+use common::{start_mock_api, test_app, wait_until, CardsMode, TestApp};
+use fuckbilibili_lib::config::{AppConfig, OverflowPolicy};
+use fuckbilibili_lib::db;
+use fuckbilibili_lib::fetcher::BilibiliFetcher;
+use fuckbilibili_lib::server;
+use serde_json::Value;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+async fn init(
+    app: &TestApp,
+) -> impl Service<actix_http::Request, Response = ServiceResponse<impl MessageBody>, Error = actix_web::Error> {
+    test::init_service(server::app(app.state.clone())).await
+}
+
+async fn post<S, B>(service: &S, path: &str, form: &[(&str, &str)]) -> String
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let req = test::TestRequest::post().uri(path).set_form(form).to_request();
+    String::from_utf8(test::call_and_read_body(service, req).await.to_vec()).unwrap()
+}
+
+async fn get<S, B>(service: &S, path: &str) -> String
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let req = test::TestRequest::get().uri(path).to_request();
+    String::from_utf8(test::call_and_read_body(service, req).await.to_vec()).unwrap()
+}
+
+#[actix_web::test]
+async fn ok_endpoint_answers() {
+    let app = test_app();
+    let service = init(&app).await;
+
+    assert_eq!(get(&service, "/ok").await, "OK");
+}
+
+#[actix_web::test]
+async fn block_and_remove_round_trip() {
+    let app = test_app();
+    let service = init(&app).await;
+
+    assert_eq!(post(&service, "/block", &[("mid", "100"), ("username", "spam")]).await, "OK");
+    // Already blocked
+    assert_eq!(post(&service, "/block", &[("mid", "100")]).await, "ERR2");
+    assert_eq!(post(&service, "/block", &[("mid", "abc")]).await, "ERR1");
+    assert_eq!(app.state.db_stats.blocked_user_count.load(Ordering::Relaxed), 1);
+    assert_eq!(get(&service, "/isExist?mid=100").await, "True");
+
+    assert_eq!(post(&service, "/remove", &[("mid", "100")]).await, "OK");
+    // No longer blocked
+    assert_eq!(post(&service, "/remove", &[("mid", "100")]).await, "ERR2");
+    assert_eq!(post(&service, "/remove", &[("mid", "-1")]).await, "ERR1");
+    assert_eq!(app.state.db_stats.blocked_user_count.load(Ordering::Relaxed), 0);
+    assert_eq!(get(&service, "/isExist?mid=100").await, "False");
+}
+
+#[actix_web::test]
+async fn temporary_blocks_expire() {
+    let app = test_app();
+    let service = init(&app).await;
+    let now = chrono::Utc::now().timestamp();
+
+    assert_eq!(post(&service, "/block", &[("mid", "7"), ("duration", "3600")]).await, "OK");
+    assert_eq!(get(&service, "/isExist?mid=7").await, "True");
+    let later = (now + 7200).to_string();
+    assert_eq!(post(&service, "/block", &[("mid", "8"), ("expires_at", &later)]).await, "OK");
+    assert_eq!(app.state.db_stats.blocked_user_count.load(Ordering::Relaxed), 2);
+
+    let past = (now - 10).to_string();
+    for form in [
+        [("duration", "0"), ("mid", "9")],
+        [("duration", "-5"), ("mid", "9")],
+        [("duration", "1h"), ("mid", "9")],
+        [("expires_at", past.as_str()), ("mid", "9")],
+    ] {
+        assert_eq!(post(&service, "/block", &form).await, "ERR1", "{:?}", form);
+    }
+    assert_eq!(post(&service, "/block", &[("mid", "9"), ("duration", "60"), ("expires_at", &later)]).await, "ERR1");
+
+    // Let mid 7's block run out
+    {
+        let conn = app.state.db.write().await;
+        db::add_user_to(&conn, 7, Some("muted"), db::DEFAULT_LIST, Some(now - 1)).unwrap();
+        app.state.index.add_block(7, db::DEFAULT_LIST, Some(now - 1));
+        db::cache_bv_mid(&conn, "BV7", 7).unwrap();
+        assert!(!db::is_user_exist(&conn, 7).unwrap());
+        assert!(db::is_user_exist(&conn, 8).unwrap());
+        assert_eq!(db::get_blocked_count(&conn).unwrap(), 1);
+    }
+    assert_eq!(get(&service, "/isExist?mid=7").await, "False");
+    let req = test::TestRequest::post().uri("/isBlockedBVS").set_form([("bvs", "BV7")]).to_request();
+    let body: Value = test::call_and_read_body_json(&service, req).await;
+    assert_eq!(body["result"][0], "False");
+
+    // The cleaner moves it to the history
+    let conn = app.state.db.write().await;
+    assert_eq!(db::purge_expired_blocks(&conn, now).unwrap(), [7]);
+    let history = db::list_block_history(&conn, 10).unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].mid, 7);
+    assert_eq!(history[0].username.as_deref(), Some("muted"));
+    assert_eq!(history[0].expires_at, Some(now - 1));
+    assert_eq!(db::list_users(&conn).unwrap().len(), 1);
+    drop(conn);
+
+    let history: Value = serde_json::from_str(&get(&service, "/history").await).unwrap();
+    assert_eq!(history[0]["mid"], 7);
+    assert_eq!(history[0]["username"], "muted");
+    assert_eq!(get(&service, "/history?limit=0").await, "[]");
+}
+
+#[actix_web::test]
+async fn blocking_again_replaces_a_temporary_block() {
+    let app = test_app();
+    let service = init(&app).await;
+
+    assert_eq!(post(&service, "/block", &[("mid", "7"), ("duration", "60"), ("username", "up")]).await, "OK");
+    // Extended, then made permanent
+    assert_eq!(post(&service, "/block", &[("mid", "7"), ("duration", "86400")]).await, "OK");
+    assert_eq!(post(&service, "/block", &[("mid", "7")]).await, "OK");
+    assert_eq!(post(&service, "/block", &[("mid", "7"), ("duration", "60")]).await, "ERR2");
+    assert_eq!(app.state.db_stats.blocked_user_count.load(Ordering::Relaxed), 1);
+
+    let conn = app.state.db.read().await;
+    let users = db::list_users(&conn).unwrap();
+    assert_eq!(users[0].username.as_deref(), Some("up"));
+    assert_eq!(users[0].expires_at, None);
+}
+
+#[actix_web::test]
+async fn disabled_block_lists_are_ignored() {
+    let app = test_app();
+    let service = init(&app).await;
+
+    assert_eq!(post(&service, "/block", &[("mid", "1")]).await, "OK");
+    assert_eq!(post(&service, "/block", &[("mid", "2"), ("list", "spoilers")]).await, "OK");
+    assert_eq!(post(&service, "/block", &[("mid", "3"), ("list", " spoilers ")]).await, "OK");
+    {
+        let conn = app.state.db.write().await;
+        db::cache_bv_mid(&conn, "BV2", 2).unwrap();
+    }
+
+    let lists: Value = serde_json::from_str(&get(&service, "/lists").await).unwrap();
+    assert_eq!(lists[0]["name"], "default");
+    assert_eq!(lists[0]["count"], 1);
+    assert_eq!(lists[1]["name"], "spoilers");
+    assert_eq!(lists[1]["count"], 2);
+    assert_eq!(lists[1]["enabled"], true);
+
+    assert_eq!(app.state.db_stats.blocked_user_count.load(Ordering::Relaxed), 3);
+    assert_eq!(post(&service, "/lists", &[("name", "spoilers"), ("enabled", "false")]).await, "OK");
+    assert_eq!(app.state.db_stats.blocked_user_count.load(Ordering::Relaxed), 1);
+    assert_eq!(get(&service, "/isExist?mid=2").await, "False");
+    assert_eq!(post(&service, "/isExistS", &[("mids", "1,2,3")]).await, r#"["True","False","False"]"#);
+    let req = test::TestRequest::post().uri("/isBlockedBVS").set_form([("bvs", "BV2")]).to_request();
+    let body: Value = test::call_and_read_body_json(&service, req).await;
+    assert_eq!(body["result"][0], "False");
+    {
+        let conn = app.state.db.read().await;
+        assert!(db::is_user_exist(&conn, 1).unwrap());
+        assert!(!db::is_user_exist(&conn, 2).unwrap());
+        // Entries are kept
+        assert_eq!(db::list_users(&conn).unwrap().len(), 3);
+    }
+    // Blocking again in another list moves the user there
+    assert_eq!(post(&service, "/block", &[("mid", "2")]).await, "OK");
+    assert_eq!(get(&service, "/isExist?mid=2").await, "True");
+    assert_eq!(post(&service, "/block", &[("mid", "2")]).await, "ERR2");
+    let lists: Value = serde_json::from_str(&get(&service, "/lists").await).unwrap();
+    assert_eq!(lists[0]["count"], 2);
+    assert_eq!(lists[1]["count"], 1);
+    assert_eq!(post(&service, "/block", &[("mid", "2"), ("list", "spoilers")]).await, "OK");
+    assert_eq!(get(&service, "/isExist?mid=2").await, "False");
+
+    // A permanent block moved with a duration stays permanent
+    assert_eq!(post(&service, "/block", &[("mid", "1"), ("list", "news"), ("duration", "60")]).await, "OK");
+    {
+        let conn = app.state.db.read().await;
+        let users = db::list_users(&conn).unwrap();
+        let moved = users.iter().find(|u| u.mid == 1).unwrap();
+        assert_eq!(moved.list.as_deref(), Some("news"));
+        assert_eq!(moved.expires_at, None);
+    }
+    {
+        // The index is given the expiry as stored
+        let conn = app.state.db.write().await;
+        let later = chrono::Utc::now().timestamp() + 60;
+        assert_eq!(db::block_in_list(&conn, 1, None, None, Some(later)).unwrap(), Some((db::DEFAULT_LIST, None)));
+    }
+    assert_eq!(get(&service, "/isExist?mid=1").await, "True");
+
+    assert_eq!(post(&service, "/lists", &[("name", "nope"), ("enabled", "true")]).await, "ERR2");
+    assert_eq!(post(&service, "/lists", &[("name", "spoilers"), ("enabled", "yes")]).await, "ERR1");
+    assert_eq!(post(&service, "/lists", &[("name", "spoilers"), ("enabled", "true")]).await, "OK");
+    assert_eq!(get(&service, "/isExist?mid=2").await, "True");
+
+    // Removing a user the count left out leaves it as it is
+    assert_eq!(post(&service, "/lists", &[("name", "spoilers"), ("enabled", "false")]).await, "OK");
+    assert_eq!(post(&service, "/remove", &[("mid", "3")]).await, "OK");
+    assert_eq!(app.state.db_stats.blocked_user_count.load(Ordering::Relaxed), 1);
+}
+
+#[actix_web::test]
+async fn block_lists_survive_a_restart_and_export() {
+    let app = test_app();
+    let service = init(&app).await;
+    assert_eq!(post(&service, "/block", &[("mid", "1"), ("list", "spam")]).await, "OK");
+    assert_eq!(post(&service, "/block", &[("mid", "2")]).await, "OK");
+    assert_eq!(post(&service, "/lists", &[("name", "spam"), ("enabled", "false")]).await, "OK");
+    app.state.db.close().await;
+
+    let (state, _) = fuckbilibili_lib::state::open(app.dir.path().join("blocked_users.db"), app.dir.path().join("config.json"));
+    assert!(!state.index.is_blocked(1));
+    assert!(state.index.is_blocked(2));
+
+    let mut conn = state.db.write().await;
+    let exported = serde_json::to_value(db::list_users(&conn).unwrap()).unwrap();
+    assert_eq!(exported, serde_json::json!([{ "mid": 1, "username": null, "list": "spam" }, { "mid": 2, "username": null }]));
+
+    let imported: Vec<db::BlockedUser> = serde_json::from_value(serde_json::json!([
+        { "mid": 3, "list": "spam" },
+        { "mid": 4, "list": "news" }
+    ]))
+    .unwrap();
+    assert_eq!(db::add_users(&mut conn, &imported).unwrap(), 2);
+    let lists = db::list_block_lists(&conn).unwrap();
+    let names: Vec<(&str, usize)> = lists.iter().map(|l| (l.name.as_str(), l.count)).collect();
+    assert_eq!(names, [("default", 1), ("spam", 2), ("news", 1)]);
+    assert!(!db::is_user_exist(&conn, 3).unwrap());
+    assert!(db::is_user_exist(&conn, 4).unwrap());
+}
+
+#[actix_web::test]
+async fn reload_picks_up_blocks_written_by_another_process() {
+    let app = test_app();
+    let service = init(&app).await;
+    assert_eq!(post(&service, "/block", &[("mid", "1")]).await, "OK");
+
+    // What the blocklist CLI does: its own connection to the same file
+    let conn = db::init_db(app.dir.path().join("blocked_users.db")).unwrap();
+    db::add_user(&conn, 2, None).unwrap();
+    db::remove_user(&conn, 1).unwrap();
+    drop(conn);
+    assert_eq!(get(&service, "/isExist?mid=2").await, "False");
+
+    assert_eq!(post(&service, "/reload", &[]).await, "OK");
+    assert_eq!(get(&service, "/isExist?mid=2").await, "True");
+    assert_eq!(get(&service, "/isExist?mid=1").await, "False");
+    assert_eq!(app.state.db_stats.blocked_user_count.load(Ordering::Relaxed), 1);
+}
+
+#[actix_web::test]
+async fn is_exist_validates_mid() {
+    let app = test_app();
+    let service = init(&app).await;
+
+    assert_eq!(get(&service, "/isExist?mid=12a").await, "ERR1");
+    assert_eq!(get(&service, "/isExist?mid=99999999999999999999").await, "ERR1");
+    assert_eq!(app.state.service_stats.req_count.load(Ordering::Relaxed), 0);
+
+    assert_eq!(get(&service, "/isExist?mid=5").await, "False");
+    assert_eq!(app.state.service_stats.req_count.load(Ordering::Relaxed), 1);
+}
+
+#[actix_web::test]
+async fn is_exist_s_answers_in_input_order() {
+    let app = test_app();
+    let service = init(&app).await;
+    post(&service, "/block", &[("mid", "2")]).await;
+    post(&service, "/block", &[("mid", "4")]).await;
+
+    let body = post(&service, "/isExistS", &[("mids", "1,2,x,4")]).await;
+
+    let results: Vec<String> = serde_json::from_str(&body).unwrap();
+    assert_eq!(results, vec!["False", "True", "ERR1", "True"]);
+}
+
+#[actix_web::test]
+async fn blocked_bvs_are_queued_and_resolved_by_the_spider() {
+    let api = start_mock_api(CardsMode::Ok);
+    let app = test_app();
+    app.start_spider(Arc::new(BilibiliFetcher::new(&api.base_url, &AppConfig::default())));
+    let service = init(&app).await;
+    post(&service, "/block", &[("mid", "31")]).await;
+
+    // Nothing cached yet: every BV is unknown and handed to the spider
+    let body = post(&service, "/isBlockedBVS", &[("bvs", "BV31,BV32,BV404")]).await;
+    let json: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(json["msg"], "OK");
+    assert_eq!(json["mid"], serde_json::json!([null, null, null]));
+    assert_eq!(json["result"], serde_json::json!(["None", "None", "None"]));
+
+    // Asking again while queued does not queue twice
+    post(&service, "/isBlockedBVS", &[("bvs", "BV31")]).await;
+    assert_eq!(app.state.spider_stats.total_received_count.load(Ordering::Relaxed), 4);
+
+    app.wait_idle().await;
+    assert_eq!(api.view_hits.load(Ordering::SeqCst), 3);
+    assert_eq!(app.state.spider_stats.fail_count.load(Ordering::Relaxed), 1);
+
+    let body = post(&service, "/isBlockedBVS", &[("bvs", "BV31,BV32,BV404")]).await;
+    let json: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(json["mid"], serde_json::json!([31, 32, null]));
+    assert_eq!(json["result"], serde_json::json!(["True", "False", "None"]));
+
+    // The failed BV is queued again and fails again
+    wait_until(|| app.state.spider_stats.fail_count.load(Ordering::Relaxed) == 2).await;
+    assert_eq!(api.view_hits.load(Ordering::SeqCst), 4);
+}
+
+#[actix_web::test]
+async fn cached_bvs_follow_block_changes() {
+    let api = start_mock_api(CardsMode::Ok);
+    let app = test_app();
+    app.start_spider(Arc::new(BilibiliFetcher::new(&api.base_url, &AppConfig::default())));
+    let service = init(&app).await;
+
+    post(&service, "/isBlockedBVS", &[("bvs", "BV50")]).await;
+    app.wait_idle().await;
+
+    let check = || async {
+        let body = post(&service, "/isBlockedBVS", &[("bvs", "BV50")]).await;
+        serde_json::from_str::<Value>(&body).unwrap()["result"][0].clone()
+    };
+    assert_eq!(check().await, "False");
+    post(&service, "/block", &[("mid", "50")]).await;
+    assert_eq!(check().await, "True");
+    post(&service, "/remove", &[("mid", "50")]).await;
+    assert_eq!(check().await, "False");
+}
+
+#[actix_web::test]
+async fn server_reports_bind_result_and_restarts() {
+    let app = test_app();
+
+    let server = server::Server::start(app.state.clone(), ("127.0.0.1", 0)).unwrap();
+    let addr = server.addr();
+    let url = format!("http://{}/ok", addr);
+    assert_eq!(reqwest::get(&url).await.unwrap().text().await.unwrap(), "OK");
+
+    // The port is taken while the server runs
+    assert!(server::Server::start(app.state.clone(), addr).is_err());
+
+    let server = server.restart(app.state.clone()).await.unwrap();
+    assert_eq!(server.addr(), addr);
+    assert_eq!(reqwest::get(&url).await.unwrap().text().await.unwrap(), "OK");
+
+    server.stop().await;
+    assert!(reqwest::get(&url).await.is_err());
+}
+
+#[actix_web::test]
+async fn is_blocked_bvs_queues_by_priority() {
+    let app = test_app();
+    let service = init(&app).await;
+
+    post(&service, "/isBlockedBVS", &[("bvs", "BV1,BV2")]).await;
+    post(&service, "/isBlockedBVS", &[("bvs", "BV3"), ("priority", "high")]).await;
+    // BV2 shows up again on a newer page
+    post(&service, "/isBlockedBVS", &[("bvs", "BV2")]).await;
+
+    let queue = &app.state.spider_queue;
+    let mut order = Vec::new();
+    while !queue.is_empty() {
+        order.push(queue.pop().await.unwrap().bvid);
+    }
+    assert_eq!(order, ["BV3", "BV2", "BV1"]);
+    assert_eq!(app.state.spider_stats.queue_size.load(Ordering::Relaxed), 3);
+}
+
+#[actix_web::test]
+async fn is_blocked_bvs_rejects_what_is_not_a_bv() {
+    let app = test_app();
+    let service = init(&app).await;
+
+    let body = post(&service, "/isBlockedBVS", &[("bvs", "BV1&ids=BV2,BV1#x,av170001,,BV3")]).await;
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["result"], serde_json::json!(["ERR1", "ERR1", "ERR1", "ERR1", "None"]));
+
+    let queue = &app.state.spider_queue;
+    assert_eq!(queue.pop().await.unwrap().bvid, "BV3");
+    assert!(queue.is_empty());
+}
+
+#[actix_web::test]
+async fn full_queue_applies_overflow_policy_without_blocking() {
+    let app = test_app();
+    let service = init(&app).await;
+    let capacity = app.state.spider_queue.capacity();
+    let backlog: Vec<String> = (0..capacity).map(|i| format!("BV{}", i)).collect();
+    post(&service, "/isBlockedBVS", &[("bvs", &backlog.join(","))]).await;
+
+    // Default policy: the oldest queued BV makes room
+    let body: Value = serde_json::from_str(&post(&service, "/isBlockedBVS", &[("bvs", "BVnew1")]).await).unwrap();
+    assert_eq!(body["result"][0], "None");
+    assert!(app.state.pending_bvs.lock().await.contains("BVnew1"));
+    assert!(!app.state.pending_bvs.lock().await.contains(&backlog[capacity - 1]));
+    assert_eq!(app.state.spider_stats.overflow_dropped.load(Ordering::Relaxed), 1);
+    assert_eq!(app.state.spider_stats.queue_size.load(Ordering::Relaxed), capacity);
+
+    app.state.spider_queue.set_overflow_policy(OverflowPolicy::Reject);
+    let body: Value = serde_json::from_str(&post(&service, "/isBlockedBVS", &[("bvs", "BVnew2,BVnew1")]).await).unwrap();
+    // Already queued BVs are not affected
+    assert_eq!(body["result"], serde_json::json!(["Busy", "None"]));
+    assert_eq!(app.state.spider_stats.overflow_rejected.load(Ordering::Relaxed), 1);
+    assert!(!app.state.pending_bvs.lock().await.contains("BVnew2"));
+}
+
+#[actix_web::test]
+async fn cancel_drops_bvs_of_a_closed_tab() {
+    let app = test_app();
+    let service = init(&app).await;
+
+    post(&service, "/isBlockedBVS", &[("bvs", "BV1,BV2"), ("session", "tab1")]).await;
+    post(&service, "/isBlockedBVS", &[("bvs", "BV2,BV3"), ("session", "tab2")]).await;
+
+    let body: Value = serde_json::from_str(&post(&service, "/cancel", &[("session", "tab1")]).await).unwrap();
+    assert_eq!(body["msg"], "OK");
+    assert_eq!(body["cancelled"], 1);
+
+    let stats = &app.state.spider_stats;
+    assert_eq!(stats.queue_size.load(Ordering::Relaxed), 2);
+    assert_eq!(stats.skipped_count.load(Ordering::Relaxed), 1);
+    assert!(!app.state.pending_bvs.lock().await.contains("BV1"));
+    assert_eq!(app.state.spider_queue.len(), 2);
+}
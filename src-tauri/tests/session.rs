@@ -0,0 +1,97 @@
+//! Login cookies: storage outside config.json, sending them and noticing
+//! when Bilibili no longer accepts them.
+
+mod common;
+
+use common::{start_mock_api, CardsMode};
+use fuckbilibili_lib::config::{AppConfig, BilibiliCookies, ConfigManager};
+use fuckbilibili_lib::fetcher::{BilibiliFetcher, FetchError, MetadataFetcher};
+use fuckbilibili_lib::state::SpiderStats;
+use std::fs;
+use std::sync::atomic::Ordering;
+
+fn cookie_config(sessdata: &str) -> AppConfig {
+    AppConfig {
+        cookies: BilibiliCookies {
+            sessdata: sessdata.to_string(),
+            buvid3: "abc-infoc".to_string(),
+        },
+        ..AppConfig::default()
+    }
+}
+
+#[test]
+fn cookies_are_saved_apart_from_config() {
+    let dir = tempfile::tempdir().unwrap();
+    let config_file = dir.path().join("config.json");
+    let cookies_file = dir.path().join("cookies.json");
+
+    ConfigManager::new(&config_file).set_config(cookie_config("secret")).unwrap();
+
+    assert!(!fs::read_to_string(&config_file).unwrap().contains("secret"));
+    assert!(fs::read_to_string(&cookies_file).unwrap().contains("secret"));
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&cookies_file).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    let reloaded = ConfigManager::new(&config_file).get_config();
+    assert_eq!(reloaded.cookies.sessdata, "secret");
+
+    // Clearing the cookies removes the file
+    ConfigManager::new(&config_file).set_config(AppConfig::default()).unwrap();
+    assert!(!cookies_file.exists());
+}
+
+#[test]
+fn cookie_header_skips_blank_values() {
+    assert_eq!(cookie_config("s").cookies.header().as_deref(), Some("SESSDATA=s; buvid3=abc-infoc"));
+    assert_eq!(cookie_config(" ").cookies.header().as_deref(), Some("buvid3=abc-infoc"));
+    assert_eq!(BilibiliCookies::default().header(), None);
+}
+
+#[actix_web::test]
+async fn spider_requests_carry_cookies() {
+    let api = start_mock_api(CardsMode::Ok);
+    let fetcher = BilibiliFetcher::new(&api.base_url, &cookie_config("valid"));
+    let stats = SpiderStats::default();
+
+    fetcher.fetch("BV1", &stats).await.unwrap();
+    assert_eq!(api.cookie.lock().unwrap().as_deref(), Some("SESSDATA=valid; buvid3=abc-infoc"));
+
+    // Cookie changes are picked up with the rest of the config
+    fetcher.apply_config(&AppConfig::default());
+    fetcher.fetch("BV2", &stats).await.unwrap();
+    assert_eq!(api.cookie.lock().unwrap().as_deref(), None);
+}
+
+#[actix_web::test]
+async fn not_logged_in_code_flags_expired_session() {
+    let api = start_mock_api(CardsMode::Ok);
+    let stats = SpiderStats::default();
+
+    // Anonymous clients get -101 too, that is not an expired session
+    let anonymous = BilibiliFetcher::new(&api.base_url, &AppConfig::default());
+    assert!(matches!(anonymous.fetch("BV101", &stats).await, Err(FetchError::Api(-101))));
+    assert!(!stats.session_expired.load(Ordering::Relaxed));
+
+    let fetcher = BilibiliFetcher::new(&api.base_url, &cookie_config("stale"));
+    assert!(matches!(fetcher.fetch("BV101", &stats).await, Err(FetchError::Api(-101))));
+    assert!(stats.session_expired.load(Ordering::Relaxed));
+}
+
+#[actix_web::test]
+async fn maintenance_verifies_the_session() {
+    let api = start_mock_api(CardsMode::Ok);
+    let stats = SpiderStats::default();
+
+    let fetcher = BilibiliFetcher::new(&api.base_url, &cookie_config("stale"));
+    fetcher.maintain(&stats).await;
+    assert!(stats.session_expired.load(Ordering::Relaxed));
+
+    fetcher.apply_config(&cookie_config("valid"));
+    fetcher.maintain(&stats).await;
+    assert!(!stats.session_expired.load(Ordering::Relaxed));
+}
@@ -0,0 +1,165 @@
+//! Spider behaviour with an in-memory fetcher and against a mock Bilibili API.
+
+mod common;
+
+use common::{dead_endpoint, start_mock_api, test_app, wait_until, CardsMode, StaticFetcher};
+use fuckbilibili_lib::config::{AppConfig, FetchStrategy};
+use fuckbilibili_lib::db;
+use fuckbilibili_lib::fetcher::{BilibiliFetcher, FetchError, MetadataFetcher};
+use fuckbilibili_lib::state::SpiderStats;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[actix_web::test]
+async fn resolved_bvs_are_cached() {
+    let app = test_app();
+    app.start_spider(Arc::new(StaticFetcher::default()));
+
+    app.enqueue(&["BV11", "BV12"]).await;
+    app.wait_idle().await;
+
+    let conn = app.state.db.read().await;
+    assert_eq!(db::get_mid_by_bv(&conn, "BV11").unwrap(), Some(11));
+    assert_eq!(db::get_mid_by_bv(&conn, "BV12").unwrap(), Some(12));
+    assert_eq!(app.state.index.get_mid_by_bv("BV11"), Some(11));
+    assert_eq!(app.state.spider_stats.bv_cache_count.load(Ordering::Relaxed), 2);
+    assert_eq!(app.state.spider_stats.fail_count.load(Ordering::Relaxed), 0);
+    assert!(app.state.pending_bvs.lock().await.is_empty());
+}
+
+#[actix_web::test]
+async fn api_errors_count_as_failures_and_free_the_bv() {
+    let app = test_app();
+    let fetcher = StaticFetcher {
+        api_errors: HashMap::from([("BV1".to_string(), -404)]),
+        ..Default::default()
+    };
+    app.start_spider(Arc::new(fetcher));
+
+    app.enqueue(&["BV1", "BV2"]).await;
+    app.wait_idle().await;
+
+    let conn = app.state.db.read().await;
+    assert_eq!(db::get_mid_by_bv(&conn, "BV1").unwrap(), None);
+    assert_eq!(db::get_mid_by_bv(&conn, "BV2").unwrap(), Some(2));
+    assert_eq!(app.state.spider_stats.fail_count.load(Ordering::Relaxed), 1);
+    // Failed BVs can be queued again by the next request
+    assert!(app.state.pending_bvs.lock().await.is_empty());
+}
+
+#[actix_web::test]
+async fn paused_spider_does_not_fetch_until_resumed() {
+    let app = test_app();
+    let fetcher = Arc::new(StaticFetcher::default());
+    app.state.spider_stats.is_paused.store(true, Ordering::Relaxed);
+    app.start_spider(fetcher.clone());
+
+    app.enqueue(&["BV3"]).await;
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(fetcher.calls.load(Ordering::SeqCst), 0);
+    assert_eq!(app.state.spider_stats.queue_size.load(Ordering::Relaxed), 1);
+
+    app.state.spider_stats.is_paused.store(false, Ordering::Relaxed);
+    app.wait_idle().await;
+    assert_eq!(fetcher.calls.load(Ordering::SeqCst), 1);
+    assert_eq!(app.state.index.get_mid_by_bv("BV3"), Some(3));
+}
+
+#[actix_web::test]
+async fn shutdown_while_paused_persists_the_queue() {
+    let app = test_app();
+    let fetcher = Arc::new(StaticFetcher::default());
+    app.state.spider_stats.is_paused.store(true, Ordering::Relaxed);
+    app.start_spider(fetcher.clone());
+
+    app.enqueue(&["BV4", "BV5"]).await;
+    wait_until(|| app.state.spider_stats.queue_size.load(Ordering::Relaxed) == 2).await;
+    app.state.shutdown_token.cancel();
+    app.state.tasks.close();
+    app.state.tasks.wait().await;
+
+    assert_eq!(fetcher.calls.load(Ordering::SeqCst), 0);
+    let mut conn = app.state.db.write().await;
+    let mut persisted = db::take_spider_queue(&mut conn, 10).unwrap();
+    persisted.sort();
+    assert_eq!(persisted, vec!["BV4".to_string(), "BV5".to_string()]);
+}
+
+#[actix_web::test]
+async fn tasks_outliving_shutdown_still_reach_the_database() {
+    let app = test_app();
+    let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+    let state = app.state.clone();
+    let task = app.state.tasks.spawn(async move {
+        // Ignores the shutdown token, like a request stuck on the network
+        let _ = rx.await;
+        let conn = state.db.write().await;
+        db::add_user(&conn, 5, None).unwrap();
+        drop(conn);
+        let conn = state.db.read().await;
+        db::get_blocked_count(&conn).unwrap()
+    });
+
+    app.state.shutdown_within(Duration::from_millis(50)).await;
+    tx.send(()).unwrap();
+    assert_eq!(task.await.unwrap(), 1);
+}
+
+#[actix_web::test]
+async fn batch_strategy_groups_queued_bvs() {
+    let api = start_mock_api(CardsMode::Ok);
+    let app = test_app();
+    let config = AppConfig {
+        fetch_strategy: FetchStrategy::Batch,
+        ..AppConfig::default()
+    };
+    app.config.set_config(config.clone()).unwrap();
+    app.start_spider(Arc::new(BilibiliFetcher::new(&api.base_url, &config)));
+
+    app.enqueue(&["BV21", "BV22", "BV23"]).await;
+    app.wait_idle().await;
+
+    assert_eq!(api.cards_hits.load(Ordering::SeqCst), 1);
+    assert_eq!(api.view_hits.load(Ordering::SeqCst), 0);
+    assert_eq!(app.state.index.get_mid_by_bv("BV22"), Some(22));
+}
+
+#[actix_web::test]
+async fn mock_api_success_and_errors() {
+    let api = start_mock_api(CardsMode::Ok);
+    let fetcher = BilibiliFetcher::new(&api.base_url, &AppConfig::default());
+    let stats = SpiderStats::default();
+
+    let meta = fetcher.fetch("BV9", &stats).await.unwrap();
+    assert_eq!(meta.owner_mid, 9);
+    assert_eq!(meta.title.as_deref(), Some("view"));
+
+    assert!(matches!(fetcher.fetch("BV404", &stats).await, Err(FetchError::Api(-404))));
+    match fetcher.fetch("BVgarbage", &stats).await {
+        Err(FetchError::Parse { body, .. }) => assert_eq!(body, "<html>busy</html>"),
+        other => panic!("expected parse error, got {:?}", other),
+    }
+    assert_eq!(stats.actual_api_req_count.load(Ordering::Relaxed), 3);
+}
+
+#[actix_web::test]
+async fn unreachable_api_reports_network_error() {
+    let fetcher = BilibiliFetcher::new(&dead_endpoint(), &AppConfig::default());
+    let stats = SpiderStats::default();
+
+    let result = fetcher.fetch("BV1", &stats).await;
+
+    assert!(matches!(result, Err(FetchError::Network(_))));
+}
+
+#[test]
+fn fetch_error_messages_include_details() {
+    let parse = FetchError::Parse {
+        error: "expected value".to_string(),
+        body: "<html>".to_string(),
+    };
+    assert_eq!(parse.to_string(), "JSON parse error: expected value. Response: <html>");
+    assert_eq!(FetchError::Api(-412).to_string(), "API error: code -412");
+}
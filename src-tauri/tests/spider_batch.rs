@@ -0,0 +1,99 @@
+//! Batch fetch strategy against a local mock of the Bilibili API.
+
+mod common;
+
+use common::{dead_endpoint, start_mock_api, CardsMode};
+use fuckbilibili_lib::config::AppConfig;
+use fuckbilibili_lib::fetcher::{BilibiliFetcher, FetchError, MetadataFetcher};
+use fuckbilibili_lib::state::SpiderStats;
+use std::sync::atomic::Ordering;
+
+fn bvids(ids: &[&str]) -> Vec<String> {
+    ids.iter().map(|id| id.to_string()).collect()
+}
+
+fn owners(results: Vec<Result<fuckbilibili_lib::fetcher::VideoMeta, FetchError>>) -> Vec<i64> {
+    results.into_iter().map(|r| r.unwrap().owner_mid).collect()
+}
+
+#[actix_web::test]
+async fn batch_resolves_all_bvs_in_one_request() {
+    let api = start_mock_api(CardsMode::Ok);
+    let fetcher = BilibiliFetcher::new(&api.base_url, &AppConfig::default());
+    let stats = SpiderStats::default();
+
+    let results = fetcher.fetch_many(&bvids(&["BV1", "BV2", "BV3"]), &stats).await;
+
+    assert_eq!(owners(results), vec![1, 2, 3]);
+    assert_eq!(api.cards_hits.load(Ordering::SeqCst), 1);
+    assert_eq!(api.view_hits.load(Ordering::SeqCst), 0);
+    assert_eq!(stats.actual_api_req_count.load(Ordering::Relaxed), 1);
+}
+
+#[actix_web::test]
+async fn batch_api_error_falls_back_to_single_requests() {
+    let api = start_mock_api(CardsMode::ApiError);
+    let fetcher = BilibiliFetcher::new(&api.base_url, &AppConfig::default());
+    let stats = SpiderStats::default();
+
+    let results = fetcher.fetch_many(&bvids(&["BV7", "BV8"]), &stats).await;
+
+    assert_eq!(owners(results), vec![7, 8]);
+    assert_eq!(api.cards_hits.load(Ordering::SeqCst), 1);
+    assert_eq!(api.view_hits.load(Ordering::SeqCst), 2);
+    assert_eq!(stats.actual_api_req_count.load(Ordering::Relaxed), 3);
+}
+
+#[actix_web::test]
+async fn batch_parse_error_falls_back_to_single_requests() {
+    let api = start_mock_api(CardsMode::Garbage);
+    let fetcher = BilibiliFetcher::new(&api.base_url, &AppConfig::default());
+    let stats = SpiderStats::default();
+
+    let err = fetcher.fetch_batch(&bvids(&["BV5", "BV6"]), &stats).await.unwrap_err();
+    assert!(matches!(err, FetchError::Parse { .. }));
+
+    let results = fetcher.fetch_many(&bvids(&["BV5", "BV6"]), &stats).await;
+    assert_eq!(owners(results), vec![5, 6]);
+    assert_eq!(api.view_hits.load(Ordering::SeqCst), 2);
+}
+
+#[actix_web::test]
+async fn throttled_batch_fails_every_bv_without_single_requests() {
+    let api = start_mock_api(CardsMode::Throttled);
+    let fetcher = BilibiliFetcher::new(&api.base_url, &AppConfig::default());
+    let stats = SpiderStats::default();
+
+    let results = fetcher.fetch_many(&bvids(&["BV1", "BV2"]), &stats).await;
+
+    assert_eq!(results.len(), 2);
+    assert!(results.iter().all(|r| matches!(r, Err(FetchError::Api(-412)))));
+    assert_eq!(api.view_hits.load(Ordering::SeqCst), 0);
+}
+
+#[actix_web::test]
+async fn unreachable_batch_fails_every_bv_without_single_requests() {
+    let fetcher = BilibiliFetcher::new(&dead_endpoint(), &AppConfig::default());
+    let stats = SpiderStats::default();
+
+    let results = fetcher.fetch_many(&bvids(&["BV1", "BV2", "BV3"]), &stats).await;
+
+    assert_eq!(results.len(), 3);
+    assert!(results.iter().all(|r| matches!(r, Err(FetchError::Network(_)))));
+    assert_eq!(stats.actual_api_req_count.load(Ordering::Relaxed), 1);
+}
+
+#[actix_web::test]
+async fn bvs_missing_from_batch_are_fetched_individually() {
+    let api = start_mock_api(CardsMode::Ok);
+    let fetcher = BilibiliFetcher::new(&api.base_url, &AppConfig::default());
+    let stats = SpiderStats::default();
+
+    let results = fetcher.fetch_many(&bvids(&["BV1", "BV404", "BV3"]), &stats).await;
+
+    assert_eq!(results[0].as_ref().unwrap().owner_mid, 1);
+    assert!(matches!(results[1], Err(FetchError::Api(-404))));
+    assert_eq!(results[2].as_ref().unwrap().owner_mid, 3);
+    assert_eq!(api.cards_hits.load(Ordering::SeqCst), 1);
+    assert_eq!(api.view_hits.load(Ordering::SeqCst), 1);
+}
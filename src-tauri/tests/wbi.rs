@@ -0,0 +1,84 @@
+//! WBI signing: known vectors, and signed requests against the mock API.
+
+mod common;
+
+use common::{start_mock_api, CardsMode, MOCK_MIXIN_KEY};
+use fuckbilibili_lib::config::AppConfig;
+use fuckbilibili_lib::fetcher::{ApiCode, BilibiliFetcher};
+use fuckbilibili_lib::state::SpiderStats;
+use fuckbilibili_lib::wbi;
+use serde::Deserialize;
+use std::sync::atomic::Ordering;
+
+// Example from Bilibili's WBI documentation
+const IMG_KEY: &str = "7cd084941338484aae1ad9425b84077c";
+const SUB_KEY: &str = "4932caff0ff746eab6f01bf08b70ac45";
+
+#[test]
+fn keys_are_taken_from_image_urls() {
+    assert_eq!(
+        wbi::key_from_url("https://i0.hdslb.com/bfs/wbi/7cd084941338484aae1ad9425b84077c.png").as_deref(),
+        Some(IMG_KEY)
+    );
+    assert_eq!(wbi::key_from_url("https://i0.hdslb.com/bfs/wbi/"), None);
+}
+
+#[test]
+fn mixin_key_matches_documentation() {
+    assert_eq!(wbi::mixin_key(IMG_KEY, SUB_KEY), MOCK_MIXIN_KEY);
+}
+
+#[test]
+fn signature_matches_documentation() {
+    let params = [("foo", "114".to_string()), ("bar", "514".to_string()), ("zab", "1919810".to_string())];
+    assert_eq!(
+        wbi::sign(&params, MOCK_MIXIN_KEY, 1702204169),
+        "bar=514&foo=114&wts=1702204169&zab=1919810&w_rid=8f6f2b5b3d485fe1886cec6a0be8c5d4"
+    );
+}
+
+#[test]
+fn values_are_filtered_and_encoded() {
+    let params = [("mid", "2".to_string()), ("keyword", "a b!c'(d)*é".to_string())];
+    assert_eq!(
+        wbi::sign(&params, MOCK_MIXIN_KEY, 1700000000),
+        "keyword=a%20bcd%C3%A9&mid=2&wts=1700000000&w_rid=6f796f6a8e25e255a3dac535eec128da"
+    );
+}
+
+#[derive(Deserialize)]
+struct Echo {
+    code: i32,
+}
+
+impl ApiCode for Echo {
+    fn code(&self) -> i32 {
+        self.code
+    }
+}
+
+#[actix_web::test]
+async fn signed_requests_reuse_cached_keys() {
+    let api = start_mock_api(CardsMode::Ok);
+    let fetcher = BilibiliFetcher::new(&api.base_url, &AppConfig::default());
+    let stats = SpiderStats::default();
+
+    for mid in ["2", "3"] {
+        let echo: Echo = fetcher.get_signed("/x/space/wbi/acc/info", &[("mid", mid.to_string())], &stats).await.unwrap();
+        assert_eq!(echo.code, 0);
+    }
+    assert_eq!(api.nav_hits.load(Ordering::SeqCst), 1);
+}
+
+#[actix_web::test]
+async fn rejected_signature_refreshes_keys_once() {
+    let api = start_mock_api(CardsMode::Ok);
+    let fetcher = BilibiliFetcher::new(&api.base_url, &AppConfig::default());
+    let stats = SpiderStats::default();
+
+    api.reject_next_signature.store(true, Ordering::SeqCst);
+    let echo: Echo = fetcher.get_signed("/x/space/wbi/acc/info", &[("mid", "2".to_string())], &stats).await.unwrap();
+
+    assert_eq!(echo.code, 0);
+    assert_eq!(api.nav_hits.load(Ordering::SeqCst), 2);
+}